@@ -0,0 +1,219 @@
+use std::ffi::c_int;
+use std::future::Future;
+use std::mem::ManuallyDrop;
+use std::path::Path;
+use std::sync::Arc;
+use std::task::{ Context, Poll, Wake, Waker };
+use std::thread::ThreadId;
+use std::time::Duration;
+use async_rusqlite::{ AlreadyClosed, Connection };
+use rusqlite::ffi;
+use crate::timer;
+
+// How many pages to copy each time we borrow the connection thread.
+const PAGES_PER_STEP: c_int = 100;
+// How long to wait before trying again if a database is busy.
+const BUSY_DELAY: Duration = Duration::from_millis(10);
+
+/// The progress of a [`backup()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct BackupProgress {
+    /// The number of pages still to be copied.
+    pub remaining: u32,
+    /// The total number of pages in the database being backed up.
+    pub page_count: u32,
+}
+
+/// Back up the `main` database of a live connection to the file at `dest`,
+/// replacing whatever is already there.
+///
+/// Pages are copied a few at a time, and the connection is free to run other
+/// queries between each step. `progress` is called after each step. If another
+/// connection writes to the database while this is happening, the backup starts
+/// again; writes via this connection are copied across as they happen.
+///
+/// If the returned future is dropped before it completes, the partial backup is
+/// abandoned (and cleaned up on the connection thread). Closing the connection via
+/// [`Connection::close`] fails while a backup is in progress, including one which
+/// has been abandoned but not yet cleaned up, so the backup is never left unfinished.
+///
+/// ```rust
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use sqliter::{ ConnectionBuilder, backup };
+///
+/// let conn = ConnectionBuilder::<rusqlite::Error>::new().open_in_memory().await?;
+/// let dir = tempfile::tempdir()?;
+///
+/// backup(&conn, dir.path().join("backup.db"), |progress| {
+///     println!("{} of {} pages left", progress.remaining, progress.page_count);
+/// }).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn backup<P, F>(conn: &Connection, dest: P, mut progress: F) -> Result<(), rusqlite::Error>
+where
+    P: AsRef<Path>,
+    F: FnMut(BackupProgress)
+{
+    let dest = dest.as_ref().to_owned();
+    let conn2 = conn.clone();
+    let mut state = conn.call(move |src| Backup::new(src, &dest, conn2)).await?;
+
+    loop {
+        let step = conn.call(move |_| Ok::<_, rusqlite::Error>(state.step())).await?;
+        match step {
+            Step::Done(res, p) => {
+                progress(p);
+                return res
+            },
+            Step::Busy(s) => {
+                state = s;
+                timer::sleep(BUSY_DELAY).await;
+            },
+            Step::Copied(s, p) => {
+                state = s;
+                progress(p);
+            },
+        }
+    }
+}
+
+// An in-progress backup. This must only be used on the connection thread,
+// since the source connection is not safe to use from elsewhere.
+struct Backup {
+    handle: *mut ffi::sqlite3_backup,
+    // Keep the destination open for as long as the backup is. This is dropped by
+    // hand, since the backup must be finished before it's closed.
+    dest: ManuallyDrop<rusqlite::Connection>,
+    // Used to get back onto the connection thread if the backup is dropped elsewhere.
+    conn: Connection,
+    thread: ThreadId,
+}
+
+// Safety: the handle is only used on the connection thread, which is checked
+// before finishing it on drop.
+unsafe impl Send for Backup {}
+
+// A backup handle being sent back to the connection thread to be finished.
+struct Abandoned(*mut ffi::sqlite3_backup);
+
+// Safety: the handle is only finished once it's on the connection thread.
+unsafe impl Send for Abandoned {}
+
+enum Step {
+    Copied(Backup, BackupProgress),
+    Busy(Backup),
+    Done(Result<(), rusqlite::Error>, BackupProgress),
+}
+
+impl Backup {
+    fn new(src: &rusqlite::Connection, dest: &Path, conn: Connection) -> Result<Backup, rusqlite::Error> {
+        let dest = rusqlite::Connection::open(dest)?;
+
+        // Safety: both connections are open, and the backup is finished
+        // before the destination connection is closed.
+        let handle = unsafe {
+            ffi::sqlite3_backup_init(dest.handle(), c"main".as_ptr(), src.handle(), c"main".as_ptr())
+        };
+        if handle.is_null() {
+            let code = unsafe { ffi::sqlite3_extended_errcode(dest.handle()) };
+            return Err(dest_error(&dest, code))
+        }
+
+        Ok(Backup { handle, dest: ManuallyDrop::new(dest), conn, thread: std::thread::current().id() })
+    }
+
+    // Copy the next few pages, finishing the backup if there's nothing left to do.
+    fn step(self) -> Step {
+        // Safety: the handle is valid until finished, which consumes self.
+        let rc = unsafe { ffi::sqlite3_backup_step(self.handle, PAGES_PER_STEP) };
+        let progress = unsafe {
+            BackupProgress {
+                remaining: ffi::sqlite3_backup_remaining(self.handle) as u32,
+                page_count: ffi::sqlite3_backup_pagecount(self.handle) as u32,
+            }
+        };
+        match rc {
+            ffi::SQLITE_OK => Step::Copied(self, progress),
+            ffi::SQLITE_BUSY | ffi::SQLITE_LOCKED => Step::Busy(self),
+            // SQLITE_DONE, or an error that finishing will report.
+            _ => Step::Done(self.finish(), progress)
+        }
+    }
+
+    fn finish(mut self) -> Result<(), rusqlite::Error> {
+        // Safety: the handle hasn't been finished yet, and is nulled so that it won't be used again.
+        let rc = unsafe { ffi::sqlite3_backup_finish(self.handle) };
+        self.handle = std::ptr::null_mut();
+        match rc {
+            ffi::SQLITE_OK => Ok(()),
+            _ => Err(dest_error(&self.dest, rc))
+        }
+    }
+}
+
+impl Drop for Backup {
+    fn drop(&mut self) {
+        // Safety: the destination isn't used again after this.
+        let dest = unsafe { ManuallyDrop::take(&mut self.dest) };
+        let abandoned = Abandoned(self.handle);
+        if abandoned.0.is_null() {
+            return
+        }
+
+        // The backup was abandoned part way through, so clean it up. That's only
+        // safe to do on the connection thread.
+        if std::thread::current().id() == self.thread {
+            unsafe { ffi::sqlite3_backup_finish(abandoned.0) };
+            return
+        }
+        // The connection can't have been closed explicitly in the meantime, since that
+        // fails while the source of a backup, so this will reach the connection thread.
+        let conn = self.conn.clone();
+        let mut finish = Box::pin(async move {
+            let _ = conn.call(move |_| {
+                let abandoned = abandoned;
+                // Safety: we're on the connection thread now, and the handle hasn't been finished.
+                unsafe { ffi::sqlite3_backup_finish(abandoned.0) };
+                drop(dest);
+                Ok::<_, AlreadyClosed>(())
+            }).await;
+        });
+
+        // Poll once here so that the cleanup is queued before anything else that's
+        // done with the connection after this, and then see it through elsewhere.
+        let mut cx = Context::from_waker(Waker::noop());
+        if finish.as_mut().poll(&mut cx).is_pending() {
+            std::thread::spawn(move || block_on(finish));
+        }
+    }
+}
+
+// Run a future to completion on the current thread.
+fn block_on<F: Future>(fut: F) -> F::Output {
+    struct Unpark(std::thread::Thread);
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = std::pin::pin!(fut);
+    loop {
+        if let Poll::Ready(out) = fut.as_mut().poll(&mut cx) {
+            return out
+        }
+        std::thread::park();
+    }
+}
+
+// Backup errors are recorded against the destination connection.
+fn dest_error(dest: &rusqlite::Connection, code: c_int) -> rusqlite::Error {
+    // Safety: the connection is open, and the message is copied before it's used again.
+    let msg = unsafe { std::ffi::CStr::from_ptr(ffi::sqlite3_errmsg(dest.handle())) };
+    rusqlite::Error::SqliteFailure(ffi::Error::new(code), Some(msg.to_string_lossy().into_owned()))
+}
@@ -1,8 +1,20 @@
-use std::path::Path;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{ Arc, Mutex };
+use std::time::SystemTime;
 use async_rusqlite::{Connection};
+use async_rusqlite::rusqlite::{
+    OpenFlags, Error::SqliteFailure, ffi::ErrorCode::CannotOpen, ffi
+};
 
 use crate::migrations::Migrations;
 use crate::error::ConnectionBuilderError;
+use crate::schema::{ self, Schema };
+use crate::maintenance::{ Housekeeping, Maintenance, MaintenanceTask };
+use crate::signal::{ self, Notify, Wait };
+use crate::snapshots::{ Snapshots, list_snapshots };
+
+type OnCloseFn = Box<dyn FnOnce(Option<rusqlite::Connection>) + Send + 'static>;
 
 /// An opinionated connection builder which ultimately hands back
 /// an [`async_rusqlite::Connection`] after checking the app ID and
@@ -13,7 +25,46 @@ pub struct ConnectionBuilder<E = rusqlite::Error> {
     // Migrations to apply
     migrations: Migrations<E>,
     // Function to call when the db thread shuts down
-    on_close: Option<Box<dyn FnOnce(Option<rusqlite::Connection>) + Send + 'static>>
+    on_close: Option<OnCloseFn>,
+    // Housekeeping to perform before on_close is called
+    housekeeping: Option<Housekeeping>,
+    // Extensions (and optional entry points) to load before migrating
+    #[cfg(feature = "load_extension")]
+    extensions: Vec<(std::path::PathBuf, Option<String>)>,
+    // SQLCipher key used to decrypt (or encrypt, if new) the database
+    #[cfg(feature = "sqlcipher")]
+    key: Option<String>,
+    // Secondary databases to migrate and attach
+    attached: Vec<Attached<E>>,
+    // Turn on SQLite's defensive settings
+    safe_defaults: bool,
+    // Check the integrity of existing databases before migrating them
+    integrity_check: Option<IntegrityCheck>,
+    // Move corrupt databases aside and start again
+    quarantine_corrupt: bool,
+    // The schema we expect to see once migrations have been applied
+    expected_schema: Option<Schema>,
+    // SQL describing the schema that we want the database to have
+    declared_schema: Option<String>,
+    // Can we drop tables and columns to make the database match the declared schema?
+    allow_destructive_schema_changes: bool,
+    // Maintenance jobs to hand back a task for
+    maintenance: Option<Maintenance>,
+    // Snapshots to hand back a task for
+    snapshots: Option<Snapshots>,
+    // The maximum size in bytes that the database may grow to
+    max_size: Option<u64>,
+}
+
+/// How thoroughly to check the integrity of a database when it's opened.
+/// See [`ConnectionBuilder::check_integrity`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegrityCheck {
+    /// Run `PRAGMA quick_check`, which skips verifying that indexes match
+    /// their tables, and is much faster as a result.
+    Quick,
+    /// Run `PRAGMA integrity_check`.
+    Full,
 }
 
 impl <E: Send + 'static> Default for ConnectionBuilder<E> {
@@ -29,6 +80,21 @@ impl <E: Send + 'static> ConnectionBuilder<E> {
             app_id: 0,
             migrations: Default::default(),
             on_close: None,
+            housekeeping: None,
+            #[cfg(feature = "load_extension")]
+            extensions: Vec::new(),
+            #[cfg(feature = "sqlcipher")]
+            key: None,
+            attached: Vec::new(),
+            safe_defaults: false,
+            integrity_check: None,
+            quarantine_corrupt: false,
+            expected_schema: None,
+            declared_schema: None,
+            allow_destructive_schema_changes: false,
+            maintenance: None,
+            snapshots: None,
+            max_size: None,
         }
     }
 
@@ -40,6 +106,42 @@ impl <E: Send + 'static> ConnectionBuilder<E> {
         self
     }
 
+    /// Turn on a set of SQLite's defensive settings, which catch various classes of
+    /// bugs and guard against maliciously crafted database files:
+    ///
+    /// - `SQLITE_DBCONFIG_DEFENSIVE`, preventing direct writes to the schema and
+    ///   other ways of deliberately corrupting the database.
+    /// - Disallowing double-quoted string literals, so that a typo like `"name"`
+    ///   is an error rather than silently becoming the string `'name'`.
+    /// - `PRAGMA trusted_schema = OFF`.
+    /// - `PRAGMA cell_size_check = ON`.
+    ///
+    /// These apply to migrations too, so migrations must also abide by them.
+    pub fn safe_defaults(mut self) -> Self {
+        self.safe_defaults = true;
+        self
+    }
+
+    /// Check the integrity of an existing database before running any migrations
+    /// against it. If any problems are found, [`ConnectionBuilderError::Corrupt`]
+    /// will be returned, listing them.
+    pub fn check_integrity(mut self, check: IntegrityCheck) -> Self {
+        self.integrity_check = Some(check);
+        self
+    }
+
+    /// If the database file is corrupt or isn't a database at all, move it (and any
+    /// `-wal` and `-shm` files) aside by renaming it to `*.corrupt-<timestamp>` (with
+    /// a number on the end if that's already taken), and then create a new database
+    /// in its place. Use [`Self::check_integrity`] to also
+    /// detect corruption that doesn't stop the database from being read.
+    ///
+    /// Use [`Self::open_detailed`] to find out whether this happened.
+    pub fn quarantine_corrupt(mut self) -> Self {
+        self.quarantine_corrupt = true;
+        self
+    }
+
     /// Set the "app ID" for this database. If opening an existing file,
     /// this Id must match else an error will be generated. This helps to
     /// ensure that the database we're trying to open is meant for the app
@@ -49,6 +151,51 @@ impl <E: Send + 'static> ConnectionBuilder<E> {
         self
     }
 
+    /// Load an SQLite extension from the shared library at the given path each time
+    /// a connection is opened. Extensions are loaded before any migrations are run, so
+    /// migrations are free to depend on them. If `entry_point` is `None`, SQLite will
+    /// try to work out the entry point itself.
+    ///
+    /// Extension loading is only enabled for as long as it takes to load the extension.
+    ///
+    /// # Safety
+    ///
+    /// Loading an extension runs arbitrary code from the given library; see
+    /// [`rusqlite::Connection::load_extension`].
+    #[cfg(feature = "load_extension")]
+    pub unsafe fn load_extension<P: AsRef<Path>>(mut self, path: P, entry_point: Option<&str>) -> Self {
+        self.extensions.push((path.as_ref().to_owned(), entry_point.map(ToOwned::to_owned)));
+        self
+    }
+
+    /// Limit the size of the main database file to roughly this many bytes, by setting
+    /// `PRAGMA max_page_count` according to the page size. Writes which would grow the
+    /// database beyond this will fail with `SQLITE_FULL`, which is reported as
+    /// [`ConnectionBuilderError::DiskFull`] while opening the database. A database
+    /// already larger than this limit won't shrink, but won't be allowed to grow.
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Perform some housekeeping (see [`Housekeeping`]) when the connection is closed,
+    /// before any function given to [`Self::on_close`] is called. Use [`Opened::close`]
+    /// to wait for this to finish.
+    pub fn housekeeping(mut self, housekeeping: Housekeeping) -> Self {
+        self.housekeeping = Some(housekeeping);
+        self
+    }
+
+    /// Set the SQLCipher key used to encrypt the database. New databases will be
+    /// encrypted with this key, and existing databases must have been encrypted
+    /// with it, else [`ConnectionBuilderError::WrongKey`] will be returned. Use
+    /// [`crate::rekey()`] to change the key of an open database.
+    #[cfg(feature = "sqlcipher")]
+    pub fn key<S: Into<String>>(mut self, secret: S) -> Self {
+        self.key = Some(secret.into());
+        self
+    }
+
     /// Add a single migration to the list, which will be responsible for
     /// upgrading the database to the version given.
     ///
@@ -84,118 +231,785 @@ impl <E: Send + 'static> ConnectionBuilder<E> {
         self
     }
 
+    /// Attach a secondary database file under the given alias, so that its tables
+    /// can be queried as `alias.table`. The file is created if it doesn't exist.
+    ///
+    /// The attached database is treated like the main one; it must have the same
+    /// app ID, tracks its own version via `alias.user_version`, and has any extensions
+    /// loaded for its migrations. The migrations given here are applied to it (and
+    /// only it) before it's attached, which happens before the main database's
+    /// migrations run, so those can refer to `alias.table` too.
+    pub fn attach<A: Into<String>, P: AsRef<Path>>(mut self, alias: A, path: P, migrations: Migrations<E>) -> Self {
+        self.attached.push(Attached {
+            alias: alias.into(),
+            path: path.as_ref().to_owned(),
+            migrations,
+        });
+        self
+    }
+
+    /// Once migrations have been applied, check that the tables, columns and indexes
+    /// in the database are what we expect, returning [`ConnectionBuilderError::SchemaDrift`]
+    /// if not. This catches manual edits that have caused the schema to drift away
+    /// from what the migrations produce.
+    pub fn verify_schema(mut self, expected: Schema) -> Self {
+        self.expected_schema = Some(expected);
+        self
+    }
+
+    /// Declare the schema that the database should have, as a set of `CREATE TABLE`,
+    /// `CREATE INDEX`, `CREATE VIEW` and `CREATE TRIGGER` statements, instead of writing
+    /// migrations by hand. Opening the database fails with
+    /// [`ConnectionBuilderError::SchemaWithMigrations`] if any migrations are given too.
+    ///
+    /// An empty database simply has this SQL run against it. Otherwise, this schema is
+    /// built in a scratch in-memory database and compared with the real one. Missing tables,
+    /// columns, indexes, views and triggers are then created, changed indexes, views and
+    /// triggers recreated, and unwanted indexes, views and triggers dropped, in a single
+    /// transaction. Dropping tables and columns, or changing existing columns (which
+    /// requires rebuilding the table), is refused with
+    /// [`ConnectionBuilderError::DestructiveSchemaChange`] unless
+    /// [`Self::allow_destructive_schema_changes`] is used.
+    ///
+    /// This does not touch the `user_version`.
+    pub fn schema<S: Into<String>>(mut self, sql: S) -> Self {
+        self.declared_schema = Some(sql.into());
+        self
+    }
+
+    /// Allow tables and columns which are not in the schema given to [`Self::schema`] to
+    /// be dropped, **deleting any data in them**, and tables with changed columns to be
+    /// rebuilt.
+    pub fn allow_destructive_schema_changes(mut self) -> Self {
+        self.allow_destructive_schema_changes = true;
+        self
+    }
+
+    /// Configure maintenance jobs to run regularly against the database. Use
+    /// [`Self::open_detailed`] to get back a [`MaintenanceTask`] which runs them.
+    pub fn maintenance(mut self, maintenance: Maintenance) -> Self {
+        self.maintenance = Some(maintenance);
+        self
+    }
+
+    /// Configure snapshots of the database to be taken regularly. Use
+    /// [`Self::open_detailed`] to get back a [`MaintenanceTask`] which takes them.
+    pub fn snapshots(mut self, snapshots: Snapshots) -> Self {
+        self.snapshots = Some(snapshots);
+        self
+    }
+
     /// Open a connection to an in-memory database.
     pub async fn open_in_memory(mut self) -> Result<Connection, ConnectionBuilderError<E>> {
-        let conn = self.connection_builder().open_in_memory().await?;
-        self.setup(&conn, true).await?;
+        let (on_close, _) = self.take_on_close();
+        let conn = connection_builder(&on_close).open_in_memory().await?;
+        self.setup(&conn, None, true).await?;
         Ok(conn)
     }
 
-    /// Open a connection to a database at some file.
-    pub async fn open<P: AsRef<Path>>(mut self, path: P) -> Result<Connection, ConnectionBuilderError<E>> {
-        use async_rusqlite::rusqlite::{
-            OpenFlags, Error::SqliteFailure, ffi::ErrorCode::CannotOpen, ffi
-        };
+    /// Open an in-memory database holding a copy of the given bytes, such as those
+    /// produced by [`crate::serialize()`]. The database is checked and migrated just
+    /// like an existing database opened with [`Self::open`] would be, so it must have
+    /// the expected [`Self::app_id`] and must not be newer than our latest migration.
+    pub async fn open_from_bytes<B: Into<Vec<u8>>>(mut self, bytes: B) -> Result<Connection, ConnectionBuilderError<E>> {
+        let mut bytes = bytes.into();
+        // In-memory databases can't use a WAL, so if the bytes came from a database
+        // in WAL mode, mark them as using a rollback journal instead.
+        if bytes.len() >= 20 && bytes[18] == 2 && bytes[19] == 2 {
+            bytes[18] = 1;
+            bytes[19] = 1;
+        }
 
-        // The default flags rusqlite's open fn uses. First we try opening
-        // and disallow creating a new DB. Then we allow creating a new DB.
-        // This allows us to know when a new DB was created and act accordingly.
-        let flags
-            = OpenFlags::SQLITE_OPEN_READ_WRITE
-            | OpenFlags::SQLITE_OPEN_URI
-            | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let (on_close, _) = self.take_on_close();
+        let conn = connection_builder(&on_close).open_in_memory().await?;
+        conn.call(move |conn| {
+            let len = bytes.len();
+            conn.deserialize_read_exact(rusqlite::MAIN_DB, &*bytes, len, false)
+        }).await?;
+        self.setup(&conn, None, false).await?;
+        Ok(conn)
+    }
 
-        let (conn, is_new) = match self.connection_builder().open_with_flags(path.as_ref(), flags).await {
+    /// Open a connection to a database at some file.
+    pub async fn open<P: AsRef<Path>>(self, path: P) -> Result<Connection, ConnectionBuilderError<E>> {
+        self.open_detailed(path).await.map(|opened| opened.connection)
+    }
+
+    /// Like [`Self::open`], but also reports whether the database had to be
+    /// quarantined and recreated (see [`Self::quarantine_corrupt`]).
+    pub async fn open_detailed<P: AsRef<Path>>(mut self, path: P) -> Result<Opened, ConnectionBuilderError<E>> {
+        let (on_close, closed) = self.take_on_close();
+        let flags = OPEN_FLAGS;
+        let (conn, is_new) = match connection_builder(&on_close).open_with_flags(path.as_ref(), flags).await {
             // All good:
             Ok(conn) => (conn, false),
             // Can't open the file; try again but allow creating it:
-            Err(SqliteFailure(ffi::Error { code, .. }, _)) if code == CannotOpen => {
+            Err(SqliteFailure(ffi::Error { code: CannotOpen, .. }, _)) => {
                 let flags = flags | OpenFlags::SQLITE_OPEN_CREATE;
-                let conn = self.connection_builder().open_with_flags(path, flags).await?;
+                let conn = connection_builder(&on_close).open_with_flags(path.as_ref(), flags).await?;
                 (conn, true)
             },
             // Something else went wrong; just return the error.
             Err(e) => return Err(e.into()),
         };
 
-        self.setup(&conn, is_new).await?;
-        Ok(conn)
+        // The tasks are stopped by `Opened::close`, so that they don't keep the connection open.
+        let mut stop = Vec::new();
+        let mut stoppable = |task: MaintenanceTask| {
+            let (notify, wait) = signal::signal();
+            stop.push(notify);
+            task.stop_on(wait)
+        };
+        let maintenance = self.maintenance.take().map(|m| stoppable(m.task(conn.clone())));
+        let snapshots = self.snapshots.take().map(|s| stoppable(s.task(conn.clone())));
+        let quarantined = self.setup(&conn, Some(path.as_ref().to_owned()), is_new).await?;
+        Ok(Opened { connection: conn, quarantined, maintenance, snapshots, stop, closed })
+    }
+
+    /// Replace the database at `target` with a copy of the one at `backup`, and then
+    /// open it like [`Self::open`] does, applying any migrations the backup is missing.
+    ///
+    /// The backup is opened read-only and checked first; it must have the expected
+    /// [`Self::app_id`], and must not be newer than our latest migration. It's then
+    /// copied next to the target and renamed over it, after removing any `-wal` and
+    /// `-shm` files left by the old database. Nothing else should have the target
+    /// open while this happens. If the checks fail, the target is left untouched.
+    pub async fn restore_from<B: AsRef<Path>, P: AsRef<Path>>(self, backup: B, target: P) -> Result<Connection, ConnectionBuilderError<E>> {
+        let target = target.as_ref().to_owned();
+        let app_id = self.app_id;
+        let latest_migration = self.migrations.latest_version();
+        #[cfg(feature = "sqlcipher")]
+        let key = self.key.clone();
+        #[cfg(not(feature = "sqlcipher"))]
+        let key = None;
+
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let backup_conn = Connection::builder().open_with_flags(backup.as_ref(), flags).await?;
+        let restore_target = target.clone();
+        backup_conn.call(move |conn| {
+            restore(conn, key, app_id, latest_migration, &restore_target)
+        }).await?;
+        drop(backup_conn);
+
+        self.open(target).await
+    }
+
+    /// Restore the latest snapshot in `dir` which was taken at or before `at` (see
+    /// [`Snapshots`]) to `target`, using [`Self::restore_from`]. If there's no such
+    /// snapshot, an [`std::io::ErrorKind::NotFound`] error is returned.
+    pub async fn restore_snapshot<D: AsRef<Path>, P: AsRef<Path>>(self, dir: D, at: SystemTime, target: P) -> Result<Connection, ConnectionBuilderError<E>> {
+        let snapshot = list_snapshots(dir.as_ref())?
+            .into_iter()
+            .rfind(|s| s.taken <= at)
+            .ok_or_else(|| {
+                let msg = format!("no snapshot in {} taken at or before {at:?}", dir.as_ref().display());
+                std::io::Error::new(std::io::ErrorKind::NotFound, msg)
+            })?;
+        self.restore_from(snapshot.path, target).await
+    }
+
+    /// Create a new database at `path` from an SQL dump (such as one written by
+    /// [`crate::dump()`]), and then open it like [`Self::open`] does, applying any
+    /// migrations that the dump is missing.
+    ///
+    /// The dump is executed in a single transaction, skipping any `BEGIN` or `COMMIT`
+    /// statements in it. The resulting `application_id` must match [`Self::app_id`],
+    /// and the `user_version` must not be newer than our latest migration. If anything
+    /// goes wrong, the new database is removed again. A file must not already exist
+    /// at `path`.
+    pub async fn open_from_dump<P: AsRef<Path>, R: Read + Send + 'static>(self, path: P, reader: R) -> Result<Connection, ConnectionBuilderError<E>> {
+        let path = path.as_ref().to_owned();
+        if path.try_exists()? {
+            let msg = format!("{} already exists", path.display());
+            return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, msg).into())
+        }
+
+        let app_id = self.app_id;
+        let latest_migration = self.migrations.latest_version();
+        #[cfg(feature = "sqlcipher")]
+        let key = self.key.clone();
+        #[cfg(not(feature = "sqlcipher"))]
+        let key = None;
+
+        let import_conn = Connection::builder().open_with_flags(&path, OPEN_FLAGS | OpenFlags::SQLITE_OPEN_CREATE).await?;
+        let res = import_conn.call(move |conn| {
+            import_dump(conn, key, reader, app_id, latest_migration)
+        }).await;
+        // Wait for the connection to close, so that the files can be removed if need be.
+        let _ = import_conn.close().await;
+
+        if let Err(e) = res {
+            for suffix in ["", "-journal", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(with_suffix(&path, suffix));
+            }
+            return Err(e)
+        }
+
+        self.open(path).await
+    }
+
+    // Build the function to call when the connection closes, which performs any
+    // housekeeping and calls the user's on_close function, and then signals that
+    // it's done.
+    fn take_on_close(&mut self) -> (SharedOnClose, Wait) {
+        let (notify, closed) = signal::signal();
+        let housekeeping = self.housekeeping.take();
+        let user_on_close = self.on_close.take();
+
+        let on_close: OnCloseFn = Box::new(move |conn| {
+            if let (Some(housekeeping), Some(conn)) = (housekeeping, &conn) {
+                housekeeping.run(conn);
+            }
+            if let Some(on_close) = user_on_close {
+                on_close(conn);
+            }
+            notify.notify();
+        });
+
+        (Arc::new(Mutex::new(Some(on_close))), closed)
     }
 
-    // A connection builder.
-    fn connection_builder(&mut self) -> async_rusqlite::ConnectionBuilder {
-        let mut builder = Connection::builder();
+    // Configure a freshly opened connection, before anything else touches the database.
+    fn prepare(&self, conn: &rusqlite::Connection) -> Result<(), rusqlite::Error> {
+        // SQLCipher needs the key before anything else happens.
+        #[cfg(feature = "sqlcipher")]
+        if let Some(key) = &self.key {
+            conn.pragma_update(None, "key", key)?;
+        }
 
-        if let Some(on_close) = self.on_close.take() {
-            builder = builder.on_close(on_close);
+        if self.safe_defaults {
+            use rusqlite::config::DbConfig;
+            conn.set_db_config(DbConfig::SQLITE_DBCONFIG_DEFENSIVE, true)?;
+            conn.set_db_config(DbConfig::SQLITE_DBCONFIG_DQS_DML, false)?;
+            conn.set_db_config(DbConfig::SQLITE_DBCONFIG_DQS_DDL, false)?;
+            conn.pragma_update(None, "trusted_schema", false)?;
+            conn.pragma_update(None, "cell_size_check", true)?;
         }
 
-        builder
+        Ok(())
     }
 
-    // Perform any setup on the opened connection.
-    async fn setup(self, conn: &Connection, is_new: bool) -> Result<(), ConnectionBuilderError<E>> {
+    // Perform any setup on the opened connection. If the database turns out to be
+    // corrupt and we've been asked to, it's moved out of the way and replaced.
+    async fn setup(self, conn: &Connection, path: Option<PathBuf>, is_new: bool) -> Result<Option<Quarantined>, ConnectionBuilderError<E>> {
         conn.call(move |conn| {
-            if is_new {
-                // Set up the app ID if this is a new DB.
-                conn.pragma_update(None, "application_id", self.app_id)?;
-            } else {
-                // Check the app ID if this is not a new DB.
-                let val: i32 = conn.query_row(
-                    "SELECT * from pragma_application_id",
-                    [],
-                    |row| row.get(0)
-                )?;
-                if val != self.app_id {
-                    return Err(ConnectionBuilderError::WrongApplicationId(val))
+            let res = self.setup_rusqlite(conn, is_new).map_err(|e| disk_full(conn, e));
+
+            // Move a corrupt database aside and start again, if asked to.
+            if let (Err(e), Some(path), true) = (&res, &path, self.quarantine_corrupt) {
+                if let Some(problems) = corruption(e) {
+                    let path = quarantine(conn, path)?;
+                    self.setup_rusqlite(conn, true).map_err(|e| disk_full(conn, e))?;
+                    return Ok(Some(Quarantined { path, problems }))
                 }
             }
 
-            // Set foreign key constraint checking.
-            conn.pragma_update(None, "foreign_keys", true)?;
-
-            // Which version is the DB at (ie do we need to run any migrations)
-            let user_version: i32 = conn.query_row(
-                "SELECT * FROM pragma_user_version",
-                [],
-                |row| row.get(0)
-            )?;
-
-            // Attempt each migration atomically. If a migration fails, we don't
-            // want the DB to have been altered.
-            let mut latest_migration_version = 0;
-            for (version, perform_in_transaction, migration) in self.migrations.iter() {
-                latest_migration_version = version;
-                if version > user_version {
-                    if perform_in_transaction {
-                        // in one transaction, apply a migration and update the db version
-                        // to reflect this. nothing happens on failure; transaction rolled back.
-                        let transaction = conn.transaction()?;
-                        migration(&transaction).map_err(ConnectionBuilderError::Migration)?;
-                        transaction.pragma_update(None, "user_version", version)?;
-                        transaction.commit()?;
-                    } else {
-                        // This is less safe, since any failure inside the migration can lead to
-                        // the database being in an invalid state. Sometimes though, we need to
-                        // control the transaction behaviour inside the migration, so this is
-                        // the best we can do.
-                        migration(conn).map_err(ConnectionBuilderError::Migration)?;
-                        conn.pragma_update(None, "user_version", version)?;
-                    }
-                }
+            res.map(|_| None)
+        }).await
+    }
+
+    // Load the extensions given to `load_extension`, if any.
+    fn load_extensions(&self, conn: &rusqlite::Connection) -> Result<(), ConnectionBuilderError<E>> {
+        #[cfg(feature = "load_extension")]
+        for (path, entry_point) in &self.extensions {
+            // Safety: the caller promised that these extensions are safe to
+            // load when they called `ConnectionBuilder::load_extension`.
+            unsafe {
+                let _guard = rusqlite::LoadExtensionGuard::new(conn)?;
+                conn.load_extension(path, entry_point.as_deref())
+            }.map_err(|error| ConnectionBuilderError::LoadExtension { path: path.clone(), error })?;
+        }
+        #[cfg(not(feature = "load_extension"))]
+        let _ = conn;
+        Ok(())
+    }
+
+    // Set up the opened connection; check it and bring it up to date.
+    fn setup_rusqlite(&self, conn: &mut rusqlite::Connection, is_new: bool) -> Result<(), ConnectionBuilderError<E>> {
+        // Tables created by migrations would look like extra tables to be dropped.
+        if self.declared_schema.is_some() && self.migrations.latest_version() > 0 {
+            return Err(ConnectionBuilderError::SchemaWithMigrations)
+        }
+
+        self.prepare(conn)?;
+
+        let res = self.check(conn, is_new);
+
+        // This is the first read of the database, so if the key is wrong
+        // then SQLCipher will complain here that it's not a database.
+        #[cfg(feature = "sqlcipher")]
+        let res = res.map_err(|e| match e {
+            ConnectionBuilderError::Db(SqliteFailure(ffi::Error { code: ffi::ErrorCode::NotADatabase, .. }, _))
+                if self.key.is_some() => ConnectionBuilderError::WrongKey,
+            e => e
+        });
+
+        res?;
+
+        // Limit the size of the database before anything else is written to it.
+        if let Some(bytes) = self.max_size {
+            let page_size: u64 = conn.pragma_query_value(None, "page_size", |row| row.get(0))?;
+            // A max_page_count of 0 would leave the database unlimited.
+            conn.pragma_update(None, "max_page_count", (bytes / page_size).max(1))?;
+        }
+
+        // Set foreign key constraint checking.
+        conn.pragma_update(None, "foreign_keys", true)?;
+
+        // Load any extensions that migrations (or the app) may rely on.
+        self.load_extensions(conn)?;
+
+        // Migrate and then attach any secondary databases, so that the main
+        // database's migrations can refer to them.
+        for attached in &self.attached {
+            let init = |conn: &rusqlite::Connection| {
+                self.prepare(conn)?;
+                self.load_extensions(conn)
+            };
+            attached.setup(self.app_id, init).and_then(|_| {
+                conn.execute("ATTACH DATABASE ?1 AS ?2", (attached.path_str()?, &attached.alias))?;
+                Ok(())
+            }).map_err(|error| ConnectionBuilderError::Attached {
+                alias: attached.alias.clone(),
+                error: Box::new(error)
+            })?;
+        }
+
+        apply_migrations(conn, &self.migrations)?;
+
+        // Bring the database in line with any declared schema. An empty database is
+        // given it exactly as written; otherwise, the normalized form is compared.
+        if let Some(sql) = &self.declared_schema {
+            let desired = Schema::from_sql(sql)?;
+            if Schema::read(conn)?.tables.is_empty() {
+                let tx = conn.transaction()?;
+                tx.execute_batch(sql)?;
+                tx.commit()?;
             }
+            let plan = schema::plan_changes(&Schema::read(conn)?, &desired, self.allow_destructive_schema_changes)
+                .map_err(ConnectionBuilderError::DestructiveSchemaChange)?;
 
-            if latest_migration_version < user_version {
-                // We don't have migrations up to the version that the db is at already.
-                // This probably means that this app is out of date. Complain, to prevent
-                // an out of date app from trying to use the newer database.
-                return Err(ConnectionBuilderError::OutOfDate {
-                    db_version: user_version,
-                    latest_migration: latest_migration_version
-                })
+            // Rebuilding tables requires foreign keys to be disabled, which can
+            // only be done outside of a transaction.
+            if plan.rebuilds_tables {
+                conn.pragma_update(None, "foreign_keys", false)?;
             }
+            let res = apply_plan(conn, &plan);
+            if plan.rebuilds_tables {
+                conn.pragma_update(None, "foreign_keys", true)?;
+            }
+            res?;
+        }
 
-            Ok(())
-        }).await
+        if let Some(expected) = &self.expected_schema {
+            let diff = Schema::read(conn)?.diff(expected);
+            if !diff.is_empty() {
+                return Err(ConnectionBuilderError::SchemaDrift(Box::new(diff)))
+            }
+        }
+
+        Ok(())
+    }
+
+    // Check that an existing database isn't corrupt and is meant for this app,
+    // or set the app ID if the database is new.
+    fn check(&self, conn: &rusqlite::Connection, is_new: bool) -> Result<(), ConnectionBuilderError<E>> {
+        if let (Some(check), false) = (self.integrity_check, is_new) {
+            let problems = check_integrity(conn, check)?;
+            if !problems.is_empty() {
+                return Err(ConnectionBuilderError::Corrupt(problems))
+            }
+        }
+
+        init_app_id(conn, self.app_id, is_new)
+    }
+}
+
+// The function to call on close is shared between connection builders, because if
+// a builder fails to open a connection, the function it was given is dropped.
+type SharedOnClose = Arc<Mutex<Option<OnCloseFn>>>;
+
+// A connection builder which calls the shared on_close function.
+fn connection_builder(on_close: &SharedOnClose) -> async_rusqlite::ConnectionBuilder {
+    let on_close = on_close.clone();
+    Connection::builder().on_close(move |conn| {
+        if let Some(on_close) = on_close.lock().unwrap().take() {
+            on_close(conn);
+        }
+    })
+}
+
+/// The result of [`ConnectionBuilder::open_detailed`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct Opened {
+    /// The open connection.
+    pub connection: Connection,
+    /// If the database was found to be corrupt and was quarantined (see
+    /// [`ConnectionBuilder::quarantine_corrupt`]), this contains the details.
+    pub quarantined: Option<Quarantined>,
+    /// If maintenance was configured (see [`ConnectionBuilder::maintenance`]),
+    /// this task must be spawned to run it.
+    pub maintenance: Option<MaintenanceTask>,
+    /// If snapshots were configured (see [`ConnectionBuilder::snapshots`]), this
+    /// task must be spawned to take them.
+    pub snapshots: Option<MaintenanceTask>,
+    // Stops the maintenance and snapshot tasks, wherever they've been spawned.
+    stop: Vec<Notify>,
+    // Completes once the connection has closed and on_close has run.
+    closed: Wait,
+}
+
+impl Opened {
+    /// Close the connection, waiting until any [`Housekeeping`] and the function given
+    /// to [`ConnectionBuilder::on_close`] have finished. The maintenance and snapshot
+    /// tasks are stopped, whether or not they've been spawned. If the connection has
+    /// been cloned elsewhere, this will also wait for those clones to be dropped.
+    pub async fn close(self) {
+        let Opened { connection, maintenance, snapshots, stop, closed, .. } = self;
+        for notify in stop {
+            notify.notify();
+        }
+        drop(connection);
+        drop(maintenance);
+        drop(snapshots);
+        closed.await
+    }
+}
+
+/// Details about a corrupt database which has been moved aside.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Quarantined {
+    /// Where the corrupt database was moved to. Any `-wal` and `-shm`
+    /// files will have been moved alongside it.
+    pub path: PathBuf,
+    /// The problems that were found with the database.
+    pub problems: Vec<String>,
+}
+
+// If the error is a sign that the database is corrupt (or not a database
+// at all), return the problems with it.
+fn corruption<E>(e: &ConnectionBuilderError<E>) -> Option<Vec<String>> {
+    use ffi::ErrorCode::{ DatabaseCorrupt, NotADatabase };
+    match e {
+        ConnectionBuilderError::Corrupt(problems) =>
+            Some(problems.clone()),
+        ConnectionBuilderError::Db(e @ SqliteFailure(ffi::Error { code: DatabaseCorrupt | NotADatabase, .. }, _)) =>
+            Some(vec![e.to_string()]),
+        _ => None
+    }
+}
+
+// Report errors caused by the disk (or database, given max_size) being full
+// as DiskFull. That's either SQLITE_FULL, or an SQLITE_IOERR caused by the
+// OS running out of space. Migrations which fail with a rusqlite::Error are
+// checked too, since that's the error type most of them use.
+fn disk_full<E: 'static>(conn: &rusqlite::Connection, e: ConnectionBuilderError<E>) -> ConnectionBuilderError<E> {
+    use std::any::Any;
+    use ffi::ErrorCode::{ DiskFull, SystemIoFailure };
+
+    let err = match &e {
+        ConnectionBuilderError::Db(err) => Some(err),
+        ConnectionBuilderError::Migration(err) => (err as &dyn Any).downcast_ref::<rusqlite::Error>(),
+        _ => None
+    };
+
+    let is_full = match err.and_then(|err| err.sqlite_error_code()) {
+        Some(DiskFull) => true,
+        Some(SystemIoFailure) => {
+            // Safety: the handle is valid for as long as `conn` is, and is
+            // only used to read the last OS error number.
+            let errno = unsafe { ffi::sqlite3_system_errno(conn.handle()) };
+            std::io::Error::from_raw_os_error(errno).kind() == std::io::ErrorKind::StorageFull
+        },
+        _ => false
+    };
+
+    match e {
+        ConnectionBuilderError::Db(err) if is_full =>
+            ConnectionBuilderError::DiskFull(err),
+        ConnectionBuilderError::Migration(err) if is_full => {
+            let err = (Box::new(err) as Box<dyn Any>).downcast::<rusqlite::Error>();
+            ConnectionBuilderError::DiskFull(*err.expect("checked to be a rusqlite::Error above"))
+        },
+        e => e
+    }
+}
+
+// Check that a database we didn't create belongs to this app, and isn't too new
+// for our migrations.
+fn check_compatible<E>(conn: &rusqlite::Connection, app_id: i32, latest_migration: i32) -> Result<(), ConnectionBuilderError<E>> {
+    let db_app_id: i32 = conn.pragma_query_value(None, "application_id", |row| row.get(0))?;
+    if db_app_id != app_id {
+        return Err(ConnectionBuilderError::WrongApplicationId(db_app_id))
+    }
+    let db_version: i32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if db_version > latest_migration {
+        return Err(ConnectionBuilderError::OutOfDate { db_version, latest_migration })
+    }
+    Ok(())
+}
+
+// Execute an SQL dump in a single transaction, and check that the result is
+// compatible with this app. Transaction statements in the dump are skipped.
+fn import_dump<E, R: Read>(conn: &mut rusqlite::Connection, key: Option<String>, mut reader: R, app_id: i32, latest_migration: i32) -> Result<(), ConnectionBuilderError<E>> {
+    use rusqlite::fallible_iterator::FallibleIterator;
+
+    if let Some(key) = &key {
+        conn.pragma_update(None, "key", key)?;
+    }
+
+    let mut sql = String::new();
+    reader.read_to_string(&mut sql)?;
+
+    let tx = conn.transaction()?;
+    let mut batch = rusqlite::Batch::new(&tx, &sql);
+    while let Some(mut stmt) = batch.next()? {
+        let stmt_sql = stmt.expanded_sql().unwrap_or_default();
+        let keyword = stmt_sql.trim_start().split(|c: char| !c.is_ascii_alphabetic()).next().unwrap_or("");
+        if ["BEGIN", "COMMIT", "END"].iter().any(|k| keyword.eq_ignore_ascii_case(k)) {
+            continue
+        }
+        // Some statements (like pragmas) return rows, which we don't need.
+        let mut rows = stmt.raw_query();
+        while rows.next()?.is_some() {}
+    }
+    tx.commit()?;
+
+    check_compatible(conn, app_id, latest_migration)
+}
+
+// Check that the backup belongs to this app and isn't too new for our migrations,
+// and then replace the database at `target` with a copy of it.
+fn restore<E>(backup: &rusqlite::Connection, key: Option<String>, app_id: i32, latest_migration: i32, target: &Path) -> Result<(), ConnectionBuilderError<E>> {
+    if let Some(key) = &key {
+        backup.pragma_update(None, "key", key)?;
+    }
+
+    check_compatible(backup, app_id, latest_migration)?;
+
+    // Copy the backup alongside the target first, so that it can be moved into
+    // place in one go. Using the backup API means we get a consistent copy even
+    // if the backup has a WAL file of its own.
+    let temp_path = with_suffix(target, ".restoring");
+    let copy = || -> Result<(), rusqlite::Error> {
+        let mut dest = rusqlite::Connection::open(&temp_path)?;
+        if let Some(key) = &key {
+            dest.pragma_update(None, "key", key)?;
+        }
+        let op = rusqlite::backup::Backup::new(backup, &mut dest)?;
+        op.run_to_completion(100, std::time::Duration::from_millis(10), None)
+    };
+    if let Err(e) = copy() {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(e.into())
+    }
+
+    if let Err(e) = std::fs::rename(&temp_path, target) {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(e.into())
     }
+
+    // A WAL file or hot journal left over from the old database must not be applied
+    // to the new one. They're only removed once it's in place, so that the old
+    // database is left intact if anything before this fails.
+    for suffix in ["-journal", "-wal", "-shm"] {
+        match std::fs::remove_file(with_suffix(target, suffix)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Move the database at the given path, and any -wal and -shm files, aside by
+// renaming them to `*.corrupt-<millis>` (plus a number if that's taken). `conn` is
+// left connected to a new, empty database at the original path. Returns the new
+// path of the database.
+fn quarantine<E>(conn: &mut rusqlite::Connection, path: &Path) -> Result<PathBuf, ConnectionBuilderError<E>> {
+    // Close the corrupt database so that we can move it.
+    *conn = rusqlite::Connection::open_in_memory()?;
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    // Never overwrite an earlier quarantine, even one from the same moment.
+    let mut suffix = format!(".corrupt-{timestamp}");
+    let mut n = 1;
+    while ["-wal", "-shm", ""].iter().any(|sidecar| with_suffix(&with_suffix(path, sidecar), &suffix).exists()) {
+        suffix = format!(".corrupt-{timestamp}-{n}");
+        n += 1;
+    }
+
+    for sidecar in ["-wal", "-shm", ""] {
+        let from = with_suffix(path, sidecar);
+        if from.exists() {
+            std::fs::rename(&from, with_suffix(&from, &suffix))?;
+        }
+    }
+
+    *conn = rusqlite::Connection::open_with_flags(path, OPEN_FLAGS | OpenFlags::SQLITE_OPEN_CREATE)?;
+    Ok(with_suffix(path, &suffix))
+}
+
+// Append some suffix to the end of a path.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+// A secondary database to attach to the main connection.
+struct Attached<E> {
+    alias: String,
+    path: PathBuf,
+    migrations: Migrations<E>,
+}
+
+impl <E> Attached<E> {
+    // Open the attached database file directly and bring it up to date. Doing this
+    // on its own connection means that its migrations apply to its own schema.
+    fn setup<F>(&self, app_id: i32, init: F) -> Result<(), ConnectionBuilderError<E>>
+    where
+        F: FnOnce(&rusqlite::Connection) -> Result<(), ConnectionBuilderError<E>>
+    {
+        let (mut conn, is_new) = open_rusqlite(&self.path)?;
+        init(&conn)?;
+        init_app_id(&conn, app_id, is_new)?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        apply_migrations(&mut conn, &self.migrations)
+    }
+
+    // Paths must be valid strings to be handed to ATTACH.
+    fn path_str(&self) -> Result<&str, rusqlite::Error> {
+        self.path.to_str().ok_or_else(|| rusqlite::Error::InvalidPath(self.path.clone()))
+    }
+}
+
+// The default flags rusqlite's open fn uses. First we try opening
+// and disallow creating a new DB. Then we allow creating a new DB.
+// This allows us to know when a new DB was created and act accordingly.
+const OPEN_FLAGS: OpenFlags
+    = OpenFlags::SQLITE_OPEN_READ_WRITE
+    .union(OpenFlags::SQLITE_OPEN_URI)
+    .union(OpenFlags::SQLITE_OPEN_NO_MUTEX);
+
+// Synchronously open a database file, returning whether it was newly created.
+fn open_rusqlite(path: &Path) -> Result<(rusqlite::Connection, bool), rusqlite::Error> {
+    match rusqlite::Connection::open_with_flags(path, OPEN_FLAGS) {
+        Ok(conn) => Ok((conn, false)),
+        Err(SqliteFailure(ffi::Error { code: CannotOpen, .. }, _)) => {
+            let conn = rusqlite::Connection::open_with_flags(path, OPEN_FLAGS | OpenFlags::SQLITE_OPEN_CREATE)?;
+            Ok((conn, true))
+        },
+        Err(e) => Err(e),
+    }
+}
+
+// Run an integrity check, returning any problems found.
+fn check_integrity(conn: &rusqlite::Connection, check: IntegrityCheck) -> Result<Vec<String>, rusqlite::Error> {
+    let pragma = match check {
+        IntegrityCheck::Quick => "quick_check",
+        IntegrityCheck::Full => "integrity_check",
+    };
+
+    let mut problems = Vec::new();
+    conn.pragma_query(None, pragma, |row| {
+        problems.push(row.get(0)?);
+        Ok(())
+    })?;
+
+    // A single "ok" row is returned if there are no problems.
+    if problems.len() == 1 && problems[0] == "ok" {
+        problems.clear();
+    }
+    Ok(problems)
+}
+
+// Run the statements needed to bring the database in line with a declared schema.
+fn apply_plan(conn: &mut rusqlite::Connection, plan: &schema::Plan) -> Result<(), rusqlite::Error> {
+    let transaction = conn.transaction()?;
+    for stmt in &plan.statements {
+        transaction.execute(stmt, ())?;
+    }
+
+    // Foreign keys aren't enforced while tables are rebuilt, so check
+    // that nothing was broken before committing.
+    if plan.rebuilds_tables {
+        let is_broken = transaction.prepare("PRAGMA foreign_key_check")?.exists([])?;
+        if is_broken {
+            return Err(SqliteFailure(
+                ffi::Error::new(ffi::SQLITE_CONSTRAINT_FOREIGNKEY),
+                Some("FOREIGN KEY constraint failed after rebuilding tables".to_owned())
+            ))
+        }
+    }
+
+    transaction.commit()
+}
+
+// Set up the app ID if this is a new DB, else check that it's what we expect.
+fn init_app_id<E>(conn: &rusqlite::Connection, app_id: i32, is_new: bool) -> Result<(), ConnectionBuilderError<E>> {
+    if is_new {
+        conn.pragma_update(None, "application_id", app_id)?;
+    } else {
+        let val: i32 = conn.query_row(
+            "SELECT * from pragma_application_id",
+            [],
+            |row| row.get(0)
+        )?;
+        if val != app_id {
+            return Err(ConnectionBuilderError::WrongApplicationId(val))
+        }
+    }
+    Ok(())
+}
+
+// Apply any migrations that the database hasn't seen yet, erroring if the
+// database is newer than the migrations that we know about.
+fn apply_migrations<E>(conn: &mut rusqlite::Connection, migrations: &Migrations<E>) -> Result<(), ConnectionBuilderError<E>> {
+    // Which version is the DB at (ie do we need to run any migrations)
+    let user_version: i32 = conn.query_row(
+        "SELECT * FROM pragma_user_version",
+        [],
+        |row| row.get(0)
+    )?;
+
+    // Attempt each migration atomically. If a migration fails, we don't
+    // want the DB to have been altered.
+    let mut latest_migration_version = 0;
+    for (version, perform_in_transaction, migration) in migrations.iter() {
+        latest_migration_version = version;
+        if version > user_version {
+            if perform_in_transaction {
+                // in one transaction, apply a migration and update the db version
+                // to reflect this. nothing happens on failure; transaction rolled back.
+                let transaction = conn.transaction()?;
+                migration(&transaction).map_err(ConnectionBuilderError::Migration)?;
+                transaction.pragma_update(None, "user_version", version)?;
+                transaction.commit()?;
+            } else {
+                // This is less safe, since any failure inside the migration can lead to
+                // the database being in an invalid state. Sometimes though, we need to
+                // control the transaction behaviour inside the migration, so this is
+                // the best we can do.
+                migration(conn).map_err(ConnectionBuilderError::Migration)?;
+                conn.pragma_update(None, "user_version", version)?;
+            }
+        }
+    }
+
+    if latest_migration_version < user_version {
+        // We don't have migrations up to the version that the db is at already.
+        // This probably means that this app is out of date. Complain, to prevent
+        // an out of date app from trying to use the newer database.
+        return Err(ConnectionBuilderError::OutOfDate {
+            db_version: user_version,
+            latest_migration: latest_migration_version
+        })
+    }
+
+    Ok(())
 }
@@ -0,0 +1,301 @@
+use std::io::{ Read, Write };
+use async_rusqlite::Connection;
+use rusqlite::types::{ Value, ValueRef };
+use crate::error::CsvError;
+use crate::schema::quote_ident;
+
+/// Import CSV into a table. The CSV must have a header row, and by default each
+/// column is put into the table column with the same name (ignoring ASCII case).
+///
+/// Values are converted according to the type affinity of the column that they're
+/// going into, so that numbers become numbers and empty values become `NULL`
+/// (except in `TEXT` columns, where they stay as empty strings). In `STRICT` tables,
+/// values which can't be converted to the column type are reported as an error,
+/// rather than being left for SQLite to reject.
+///
+/// Rows are committed in batches, and the connection is free to run other queries
+/// in between. If an error occurs, batches that have already been committed remain.
+///
+/// ```rust
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use sqliter::{ ConnectionBuilder, CsvImport };
+///
+/// let conn = ConnectionBuilder::new()
+///     .add_migration(1, |conn| conn.execute_batch("
+///         CREATE TABLE user (id INTEGER PRIMARY KEY, name TEXT NOT NULL) STRICT;
+///     "))
+///     .open_in_memory()
+///     .await?;
+///
+/// let csv = "ID,Full Name,Notes\n1,James,likes tea\n2,Bob,\n";
+/// let imported = CsvImport::new("user")
+///     .map("Full Name", "name")
+///     .ignore("Notes")
+///     .import(&conn, csv.as_bytes())
+///     .await?;
+/// assert_eq!(imported, 2);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct CsvImport {
+    table: String,
+    // CSV headers and the columns they go into, or None if they're ignored.
+    mapping: Vec<(String, Option<String>)>,
+    batch_size: usize,
+}
+
+impl CsvImport {
+    /// Configure an import into the given table in the `main` database.
+    pub fn new<S: Into<String>>(table: S) -> Self {
+        CsvImport {
+            table: table.into(),
+            mapping: Vec::new(),
+            batch_size: 1000,
+        }
+    }
+
+    /// Put values from the CSV column with the given header into the given table column.
+    pub fn map<H: Into<String>, C: Into<String>>(mut self, header: H, column: C) -> Self {
+        self.mapping.push((header.into(), Some(column.into())));
+        self
+    }
+
+    /// Ignore the CSV column with the given header.
+    pub fn ignore<H: Into<String>>(mut self, header: H) -> Self {
+        self.mapping.push((header.into(), None));
+        self
+    }
+
+    /// Commit after every `rows` rows. Defaults to 1000.
+    pub fn batch_size(mut self, rows: usize) -> Self {
+        self.batch_size = rows.max(1);
+        self
+    }
+
+    /// Import CSV from the given reader, returning the number of rows imported.
+    pub async fn import<R: Read + Send + 'static>(self, conn: &Connection, reader: R) -> Result<u64, CsvError> {
+        let mut state = conn.call(move |conn| {
+            let mut reader = ::csv::Reader::from_reader(reader);
+            let insert = self.plan(conn, reader.headers()?)?;
+            Ok::<_, CsvError>(ImportState { reader, insert, batch_size: self.batch_size })
+        }).await?;
+
+        let mut total = 0;
+        loop {
+            let (s, imported, done) = conn.call(move |conn| {
+                let (imported, done) = state.import_batch(conn)?;
+                Ok::<_, CsvError>((state, imported, done))
+            }).await?;
+            state = s;
+            total += imported;
+            if done {
+                return Ok(total)
+            }
+        }
+    }
+
+    // Work out which column each CSV value goes into, and how to convert it.
+    fn plan(&self, conn: &rusqlite::Connection, headers: &::csv::StringRecord) -> Result<Insert, CsvError> {
+        let strict: bool = conn.query_row(
+            "SELECT strict FROM pragma_table_list WHERE schema = 'main' AND type = 'table' AND name = ?1",
+            [&self.table],
+            |row| row.get(0)
+        ).map_err(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => CsvError::UnknownTable(self.table.clone()),
+            e => e.into()
+        })?;
+
+        let mut stmt = conn.prepare("SELECT name, type FROM pragma_table_xinfo(?1, 'main') WHERE hidden = 0")?;
+        let columns = stmt
+            .query_map([&self.table], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut targets = Vec::new();
+        let mut names = Vec::new();
+        for header in headers {
+            let column = match self.mapping.iter().find(|(h, _)| h == header) {
+                Some((_, Some(column))) => column.as_str(),
+                Some((_, None)) => {
+                    targets.push(None);
+                    continue
+                },
+                None => header,
+            };
+            let (name, decl_type) = columns.iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(column))
+                .ok_or_else(|| CsvError::UnknownColumn { table: self.table.clone(), column: column.to_owned() })?;
+
+            targets.push(Some(Target { column: name.clone(), affinity: Affinity::of(decl_type), strict }));
+            names.push(quote_ident(name));
+        }
+
+        let table = quote_ident(&self.table);
+        let sql = if names.is_empty() {
+            format!("INSERT INTO {table} DEFAULT VALUES")
+        } else {
+            let placeholders = vec!["?"; names.len()].join(",");
+            format!("INSERT INTO {table} ({}) VALUES ({placeholders})", names.join(","))
+        };
+        Ok(Insert { sql, targets })
+    }
+}
+
+struct ImportState<R> {
+    reader: ::csv::Reader<R>,
+    insert: Insert,
+    batch_size: usize,
+}
+
+struct Insert {
+    sql: String,
+    // One for each CSV column, or None if the column is ignored.
+    targets: Vec<Option<Target>>,
+}
+
+struct Target {
+    column: String,
+    affinity: Affinity,
+    strict: bool,
+}
+
+impl <R: Read> ImportState<R> {
+    // Import up to a batch of rows in one transaction, returning how many were
+    // imported and whether we reached the end of the CSV.
+    fn import_batch(&mut self, conn: &mut rusqlite::Connection) -> Result<(u64, bool), CsvError> {
+        let tx = conn.transaction()?;
+        let mut stmt = tx.prepare_cached(&self.insert.sql)?;
+        let mut record = ::csv::StringRecord::new();
+        let mut imported = 0;
+
+        while imported < self.batch_size as u64 {
+            if !self.reader.read_record(&mut record)? {
+                drop(stmt);
+                tx.commit()?;
+                return Ok((imported, true))
+            }
+
+            let line = record.position().map(|p| p.line()).unwrap_or(0);
+            let mut values = Vec::new();
+            for (field, target) in record.iter().zip(&self.insert.targets) {
+                let Some(target) = target else { continue };
+                let value = target.affinity.coerce(field, target.strict).ok_or_else(|| {
+                    CsvError::InvalidValue { line, column: target.column.clone(), value: field.to_owned() }
+                })?;
+                values.push(value);
+            }
+            stmt.execute(rusqlite::params_from_iter(values))?;
+            imported += 1;
+        }
+
+        drop(stmt);
+        tx.commit()?;
+        Ok((imported, false))
+    }
+}
+
+// SQLite's type affinities; see <https://sqlite.org/datatype3.html#type_affinity>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Affinity {
+    Integer,
+    Text,
+    Blob,
+    Real,
+    Numeric,
+}
+
+impl Affinity {
+    // Work out the affinity of a column from its declared type, as SQLite does.
+    fn of(decl_type: &str) -> Affinity {
+        let decl_type = decl_type.to_ascii_uppercase();
+        let has = |s: &str| decl_type.contains(s);
+        if has("INT") {
+            Affinity::Integer
+        } else if has("CHAR") || has("CLOB") || has("TEXT") {
+            Affinity::Text
+        } else if has("BLOB") || decl_type.is_empty() {
+            Affinity::Blob
+        } else if has("REAL") || has("FLOA") || has("DOUB") {
+            Affinity::Real
+        } else {
+            Affinity::Numeric
+        }
+    }
+
+    // Convert a CSV value for a column with this affinity. None is returned if the
+    // value can't be stored in a column of a STRICT table.
+    fn coerce(self, field: &str, strict: bool) -> Option<Value> {
+        let trimmed = field.trim();
+        if self == Affinity::Text {
+            return Some(Value::Text(field.to_owned()))
+        }
+        if trimmed.is_empty() {
+            return Some(Value::Null)
+        }
+
+        let int = trimmed.parse::<i64>().ok();
+        let real = trimmed.parse::<f64>().ok().filter(|f| f.is_finite());
+        let value = match (self, int, real) {
+            (Affinity::Integer | Affinity::Numeric, Some(n), _) => Value::Integer(n),
+            // Like SQLite, store reals with no fractional part as integers where possible.
+            (Affinity::Integer | Affinity::Numeric, None, Some(f)) if f.fract() == 0.0 && f.abs() < 9.0e18 => Value::Integer(f as i64),
+            // STRICT INTEGER columns won't accept anything else that looks like a real.
+            (Affinity::Integer, None, Some(_)) if strict => return None,
+            (Affinity::Integer | Affinity::Numeric | Affinity::Real, _, Some(f)) => Value::Real(f),
+            // STRICT tables only accept blobs in BLOB columns.
+            (Affinity::Blob, _, _) if strict => Value::Blob(field.as_bytes().to_vec()),
+            // STRICT tables have no NUMERIC columns, but ANY columns accept anything.
+            (Affinity::Integer | Affinity::Real, _, _) if strict => return None,
+            _ => Value::Text(field.to_owned()),
+        };
+        Some(value)
+    }
+}
+
+/// Run a query and write the results as CSV to `out`, with a header row naming
+/// the columns. `NULL`s are written as empty values, and blobs are hex encoded.
+/// The writer is handed back once everything has been written.
+///
+/// ```rust
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use sqliter::{ ConnectionBuilder, export_csv };
+///
+/// let conn = ConnectionBuilder::<rusqlite::Error>::new().open_in_memory().await?;
+/// let csv = export_csv(&conn, "SELECT 1 AS n, 'a,b' AS s, NULL AS x", (), Vec::new()).await?;
+/// assert_eq!(String::from_utf8(csv)?, "n,s,x\n1,\"a,b\",\n");
+/// # Ok(())
+/// # }
+/// ```
+pub async fn export_csv<P, W>(conn: &Connection, sql: &str, params: P, out: W) -> Result<W, CsvError>
+where
+    P: rusqlite::Params + Send + 'static,
+    W: Write + Send + 'static
+{
+    let sql = sql.to_owned();
+    conn.call(move |conn| {
+        let mut writer = ::csv::Writer::from_writer(out);
+        let mut stmt = conn.prepare(&sql)?;
+        writer.write_record(stmt.column_names())?;
+
+        let column_count = stmt.column_count();
+        let mut rows = stmt.query(params)?;
+        let mut record = Vec::with_capacity(column_count);
+        while let Some(row) = rows.next()? {
+            record.clear();
+            for idx in 0..column_count {
+                record.push(match row.get_ref(idx)? {
+                    ValueRef::Null => String::new(),
+                    ValueRef::Integer(n) => n.to_string(),
+                    ValueRef::Real(f) => format!("{f:?}"),
+                    ValueRef::Text(text) => String::from_utf8_lossy(text).into_owned(),
+                    ValueRef::Blob(blob) => blob.iter().map(|b| format!("{b:02x}")).collect(),
+                });
+            }
+            writer.write_record(&record)?;
+        }
+
+        writer.into_inner().map_err(|e| CsvError::Io(e.into_error()))
+    }).await
+}
@@ -0,0 +1,156 @@
+use std::io::Write;
+use async_rusqlite::Connection;
+use rusqlite::types::ValueRef;
+use crate::error::DumpError;
+use crate::schema::quote_ident;
+
+/// Write an SQL dump of the `main` database on the given connection to `out`, much
+/// like the `sqlite3` CLI's `.dump` command does. The writer is handed back once the
+/// dump is complete. Consider wrapping it in a [`std::io::BufWriter`].
+///
+/// The dump is a single transaction which sets the `application_id` and `user_version`,
+/// then creates each table and inserts its rows, and then creates any indexes, triggers
+/// and views. The data is read in a single transaction, so it's consistent even if the
+/// database is being written to elsewhere.
+///
+/// ```rust
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use sqliter::{ ConnectionBuilder, dump };
+///
+/// let conn = ConnectionBuilder::new()
+///     .add_migration(1, |conn| conn.execute_batch("
+///         CREATE TABLE user (id INTEGER PRIMARY KEY, name TEXT);
+///         INSERT INTO user (name) VALUES ('James');
+///     "))
+///     .open_in_memory()
+///     .await?;
+///
+/// let sql = dump(&conn, Vec::new()).await?;
+/// assert!(String::from_utf8(sql)?.contains("INSERT INTO \"user\" VALUES(1,'James');"));
+/// # Ok(())
+/// # }
+/// ```
+pub async fn dump<W: Write + Send + 'static>(conn: &Connection, mut out: W) -> Result<W, DumpError> {
+    conn.call(move |conn| {
+        dump_rusqlite(conn, &mut out)?;
+        out.flush()?;
+        Ok(out)
+    }).await
+}
+
+fn dump_rusqlite<W: Write>(conn: &mut rusqlite::Connection, out: &mut W) -> Result<(), DumpError> {
+    // Read everything from one snapshot of the database.
+    let tx = conn.transaction()?;
+
+    let app_id: i32 = tx.pragma_query_value(None, "application_id", |row| row.get(0))?;
+    let user_version: i32 = tx.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+    writeln!(out, "PRAGMA foreign_keys=OFF;")?;
+    writeln!(out, "BEGIN TRANSACTION;")?;
+    writeln!(out, "PRAGMA application_id = {app_id};")?;
+    writeln!(out, "PRAGMA user_version = {user_version};")?;
+
+    // Tables first, in the order they were created, along with their rows. Shadow
+    // tables belonging to virtual tables are filled in by SQLite, so skip those.
+    let mut stmt = tx.prepare("
+        SELECT s.name, s.sql FROM sqlite_schema s
+        JOIN pragma_table_list l ON l.name = s.name AND l.schema = 'main'
+        WHERE s.type = 'table' AND s.sql IS NOT NULL AND l.type != 'shadow'
+        ORDER BY s.name = 'sqlite_sequence', s.rowid
+    ")?;
+    let tables = stmt
+        .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (name, sql) in tables {
+        // SQLite creates sqlite_sequence itself when needed, so just fill it in.
+        if name == "sqlite_sequence" {
+            writeln!(out, "DELETE FROM sqlite_sequence;")?;
+        } else if !name.starts_with("sqlite_") {
+            writeln!(out, "{sql};")?;
+        } else {
+            continue
+        }
+        dump_rows(&tx, &name, out)?;
+    }
+
+    // Then everything else, once the tables they depend on exist.
+    let mut stmt = tx.prepare("
+        SELECT sql FROM sqlite_schema
+        WHERE type != 'table' AND sql IS NOT NULL AND name NOT LIKE 'sqlite\\_%' ESCAPE '\\'
+        ORDER BY rowid
+    ")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        writeln!(out, "{};", row.get::<_, String>(0)?)?;
+    }
+
+    writeln!(out, "COMMIT;")?;
+    Ok(())
+}
+
+// Write an INSERT statement for each row in a table.
+fn dump_rows<W: Write>(conn: &rusqlite::Connection, table: &str, out: &mut W) -> Result<(), DumpError> {
+    // Generated columns can't be inserted into, so name the columns if there are any.
+    let mut stmt = conn.prepare("SELECT name, hidden FROM pragma_table_xinfo(?1)")?;
+    let columns = stmt
+        .query_map([table], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i32>(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let has_generated = columns.iter().any(|(_, hidden)| *hidden != 0);
+    let columns: Vec<_> = columns.into_iter()
+        .filter(|(_, hidden)| *hidden == 0)
+        .map(|(name, _)| quote_ident(&name))
+        .collect();
+
+    let table = quote_ident(table);
+    let insert = if has_generated {
+        format!("INSERT INTO {table}({}) VALUES(", columns.join(","))
+    } else {
+        format!("INSERT INTO {table} VALUES(")
+    };
+
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM {table}", columns.join(",")))?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        out.write_all(insert.as_bytes())?;
+        for idx in 0..columns.len() {
+            if idx > 0 {
+                out.write_all(b",")?;
+            }
+            write_value(out, row.get_ref(idx)?)?;
+        }
+        out.write_all(b");\n")?;
+    }
+    Ok(())
+}
+
+// Write a value as an SQL literal which reads back as the same value.
+fn write_value<W: Write>(out: &mut W, value: ValueRef<'_>) -> std::io::Result<()> {
+    match value {
+        ValueRef::Null => write!(out, "NULL"),
+        ValueRef::Integer(n) => write!(out, "{n}"),
+        // SQLite reads out of range numbers as infinity. NaN is stored as NULL.
+        ValueRef::Real(f) if f.is_infinite() => write!(out, "{}1e999", if f < 0.0 { "-" } else { "" }),
+        // Debug formatting gives the shortest string which round trips, and
+        // keeps a decimal point so that the value is read back as a REAL.
+        ValueRef::Real(f) => write!(out, "{f:?}"),
+        ValueRef::Text(text) => {
+            out.write_all(b"'")?;
+            for (idx, part) in text.split(|&b| b == b'\'').enumerate() {
+                if idx > 0 {
+                    out.write_all(b"''")?;
+                }
+                out.write_all(part)?;
+            }
+            out.write_all(b"'")
+        },
+        ValueRef::Blob(blob) => {
+            write!(out, "X'")?;
+            for byte in blob {
+                write!(out, "{byte:02X}")?;
+            }
+            write!(out, "'")
+        },
+    }
+}
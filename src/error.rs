@@ -1,4 +1,6 @@
 
+use crate::schema::SchemaDiff;
+
 #[derive(Debug)]
 #[non_exhaustive]
 pub enum ConnectionBuilderError<E = rusqlite::Error> {
@@ -6,7 +8,18 @@ pub enum ConnectionBuilderError<E = rusqlite::Error> {
     WrongApplicationId(i32),
     OutOfDate { db_version: i32, latest_migration: i32 },
     Db(rusqlite::Error),
-    Migration(E)
+    Migration(E),
+    Attached { alias: String, error: Box<ConnectionBuilderError<E>> },
+    Corrupt(Vec<String>),
+    Io(std::io::Error),
+    SchemaDrift(Box<SchemaDiff>),
+    DestructiveSchemaChange(Box<SchemaDiff>),
+    SchemaWithMigrations,
+    DiskFull(rusqlite::Error),
+    #[cfg(feature = "load_extension")]
+    LoadExtension { path: std::path::PathBuf, error: rusqlite::Error },
+    #[cfg(feature = "sqlcipher")]
+    WrongKey,
 }
 
 impl <E: std::fmt::Display> std::fmt::Display for ConnectionBuilderError<E> {
@@ -21,7 +34,27 @@ impl <E: std::fmt::Display> std::fmt::Display for ConnectionBuilderError<E> {
             ConnectionBuilderError::Db(err) =>
                 write!(f, "Database error: {err}"),
             ConnectionBuilderError::Migration(err) =>
-                write!(f, "Migration error: {err}")
+                write!(f, "Migration error: {err}"),
+            ConnectionBuilderError::Attached { alias, error } =>
+                write!(f, "Error setting up attached database '{alias}': {error}"),
+            ConnectionBuilderError::Corrupt(problems) =>
+                write!(f, "Database is corrupt: {}", problems.join("; ")),
+            ConnectionBuilderError::Io(err) =>
+                write!(f, "IO error: {err}"),
+            ConnectionBuilderError::SchemaDrift(diff) =>
+                write!(f, "Database schema is not as expected; {diff}"),
+            ConnectionBuilderError::DestructiveSchemaChange(diff) =>
+                write!(f, "Database schema cannot be updated without destructive changes; {diff}"),
+            ConnectionBuilderError::SchemaWithMigrations =>
+                write!(f, "A declared schema cannot be combined with migrations"),
+            ConnectionBuilderError::DiskFull(err) =>
+                write!(f, "Out of space: {err}"),
+            #[cfg(feature = "load_extension")]
+            ConnectionBuilderError::LoadExtension { path, error } =>
+                write!(f, "Could not load extension {}: {error}", path.display()),
+            #[cfg(feature = "sqlcipher")]
+            ConnectionBuilderError::WrongKey =>
+                write!(f, "Wrong key; the database could not be decrypted"),
         }
     }
 }
@@ -31,9 +64,20 @@ impl <E: std::error::Error + 'static> std::error::Error for ConnectionBuilderErr
         match self {
             ConnectionBuilderError::UnexpectedlyClosed |
             ConnectionBuilderError::WrongApplicationId(_) |
-            ConnectionBuilderError::OutOfDate { .. } => None,
+            ConnectionBuilderError::OutOfDate { .. } |
+            ConnectionBuilderError::Corrupt(_) |
+            ConnectionBuilderError::SchemaDrift(_) |
+            ConnectionBuilderError::DestructiveSchemaChange(_) |
+            ConnectionBuilderError::SchemaWithMigrations => None,
+            #[cfg(feature = "sqlcipher")]
+            ConnectionBuilderError::WrongKey => None,
             ConnectionBuilderError::Db(err) => Some(err),
+            ConnectionBuilderError::DiskFull(err) => Some(err),
             ConnectionBuilderError::Migration(err) => Some(err),
+            ConnectionBuilderError::Attached { error, .. } => Some(&**error),
+            ConnectionBuilderError::Io(err) => Some(err),
+            #[cfg(feature = "load_extension")]
+            ConnectionBuilderError::LoadExtension { error, .. } => Some(error),
         }
     }
 }
@@ -44,8 +88,304 @@ impl <E> From<rusqlite::Error> for ConnectionBuilderError<E> {
     }
 }
 
+impl <E> From<std::io::Error> for ConnectionBuilderError<E> {
+    fn from(value: std::io::Error) -> Self {
+        ConnectionBuilderError::Io(value)
+    }
+}
+
 impl <E> From<async_rusqlite::AlreadyClosed> for ConnectionBuilderError<E> {
     fn from(_value: async_rusqlite::AlreadyClosed) -> Self {
         ConnectionBuilderError::UnexpectedlyClosed
     }
-}
\ No newline at end of file
+}
+
+/// An error producing or reading an SQL dump. See [`crate::dump()`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DumpError {
+    UnexpectedlyClosed,
+    Db(rusqlite::Error),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for DumpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DumpError::UnexpectedlyClosed =>
+                write!(f, "Connection unexpectedly closed"),
+            DumpError::Db(err) =>
+                write!(f, "Database error: {err}"),
+            DumpError::Io(err) =>
+                write!(f, "IO error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for DumpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DumpError::UnexpectedlyClosed => None,
+            DumpError::Db(err) => Some(err),
+            DumpError::Io(err) => Some(err),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for DumpError {
+    fn from(value: rusqlite::Error) -> Self {
+        DumpError::Db(value)
+    }
+}
+
+impl From<std::io::Error> for DumpError {
+    fn from(value: std::io::Error) -> Self {
+        DumpError::Io(value)
+    }
+}
+
+impl From<async_rusqlite::AlreadyClosed> for DumpError {
+    fn from(_value: async_rusqlite::AlreadyClosed) -> Self {
+        DumpError::UnexpectedlyClosed
+    }
+}
+
+/// An error taking a snapshot. See [`crate::Snapshots`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SnapshotError {
+    UnexpectedlyClosed,
+    Rusqlite(rusqlite::Error),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::UnexpectedlyClosed =>
+                write!(f, "Connection unexpectedly closed"),
+            SnapshotError::Rusqlite(err) =>
+                write!(f, "Database error: {err}"),
+            SnapshotError::Io(err) =>
+                write!(f, "IO error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SnapshotError::UnexpectedlyClosed => None,
+            SnapshotError::Rusqlite(err) => Some(err),
+            SnapshotError::Io(err) => Some(err),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for SnapshotError {
+    fn from(value: rusqlite::Error) -> Self {
+        SnapshotError::Rusqlite(value)
+    }
+}
+
+impl From<std::io::Error> for SnapshotError {
+    fn from(value: std::io::Error) -> Self {
+        SnapshotError::Io(value)
+    }
+}
+
+impl From<async_rusqlite::AlreadyClosed> for SnapshotError {
+    fn from(_value: async_rusqlite::AlreadyClosed) -> Self {
+        SnapshotError::UnexpectedlyClosed
+    }
+}
+
+/// An error exporting or importing JSON. See [`crate::export_json()`] and [`crate::import_json()`].
+#[cfg(feature = "json")]
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum JsonError {
+    UnexpectedlyClosed,
+    Db(rusqlite::Error),
+    UnknownTable(String),
+    UnknownColumn { table: String, column: String },
+    Invalid(String),
+}
+
+#[cfg(feature = "json")]
+impl std::fmt::Display for JsonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JsonError::UnexpectedlyClosed =>
+                write!(f, "Connection unexpectedly closed"),
+            JsonError::Db(err) =>
+                write!(f, "Database error: {err}"),
+            JsonError::UnknownTable(table) =>
+                write!(f, "No such table: {table}"),
+            JsonError::UnknownColumn { table, column } =>
+                write!(f, "No such column in table {table}: {column}"),
+            JsonError::Invalid(msg) =>
+                write!(f, "Invalid JSON: {msg}"),
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+impl std::error::Error for JsonError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            JsonError::Db(err) => Some(err),
+            JsonError::UnexpectedlyClosed |
+            JsonError::UnknownTable(_) |
+            JsonError::UnknownColumn { .. } |
+            JsonError::Invalid(_) => None,
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+impl From<rusqlite::Error> for JsonError {
+    fn from(value: rusqlite::Error) -> Self {
+        JsonError::Db(value)
+    }
+}
+
+#[cfg(feature = "json")]
+impl From<async_rusqlite::AlreadyClosed> for JsonError {
+    fn from(_value: async_rusqlite::AlreadyClosed) -> Self {
+        JsonError::UnexpectedlyClosed
+    }
+}
+
+/// An error importing or exporting CSV. See [`crate::CsvImport`] and [`crate::export_csv()`].
+#[cfg(feature = "csv")]
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum CsvError {
+    UnexpectedlyClosed,
+    Db(rusqlite::Error),
+    Csv(csv::Error),
+    Io(std::io::Error),
+    UnknownTable(String),
+    UnknownColumn { table: String, column: String },
+    InvalidValue { line: u64, column: String, value: String },
+}
+
+#[cfg(feature = "csv")]
+impl std::fmt::Display for CsvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CsvError::UnexpectedlyClosed =>
+                write!(f, "Connection unexpectedly closed"),
+            CsvError::Db(err) =>
+                write!(f, "Database error: {err}"),
+            CsvError::Csv(err) =>
+                write!(f, "CSV error: {err}"),
+            CsvError::Io(err) =>
+                write!(f, "IO error: {err}"),
+            CsvError::UnknownTable(table) =>
+                write!(f, "No such table: {table}"),
+            CsvError::UnknownColumn { table, column } =>
+                write!(f, "No such column in table {table}: {column}"),
+            CsvError::InvalidValue { line, column, value } =>
+                write!(f, "Invalid value for column {column} on line {line}: {value:?}"),
+        }
+    }
+}
+
+#[cfg(feature = "csv")]
+impl std::error::Error for CsvError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CsvError::Db(err) => Some(err),
+            CsvError::Csv(err) => Some(err),
+            CsvError::Io(err) => Some(err),
+            CsvError::UnexpectedlyClosed |
+            CsvError::UnknownTable(_) |
+            CsvError::UnknownColumn { .. } |
+            CsvError::InvalidValue { .. } => None,
+        }
+    }
+}
+
+#[cfg(feature = "csv")]
+impl From<rusqlite::Error> for CsvError {
+    fn from(value: rusqlite::Error) -> Self {
+        CsvError::Db(value)
+    }
+}
+
+#[cfg(feature = "csv")]
+impl From<csv::Error> for CsvError {
+    fn from(value: csv::Error) -> Self {
+        CsvError::Csv(value)
+    }
+}
+
+#[cfg(feature = "csv")]
+impl From<std::io::Error> for CsvError {
+    fn from(value: std::io::Error) -> Self {
+        CsvError::Io(value)
+    }
+}
+
+#[cfg(feature = "csv")]
+impl From<async_rusqlite::AlreadyClosed> for CsvError {
+    fn from(_value: async_rusqlite::AlreadyClosed) -> Self {
+        CsvError::UnexpectedlyClosed
+    }
+}
+
+/// An error recording, undoing, redoing or syncing changes. See [`crate::UndoStack`]
+/// and [`crate::Replica`].
+#[cfg(feature = "session")]
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SessionError {
+    UnexpectedlyClosed,
+    Db(rusqlite::Error),
+    Conflict,
+    InvalidChanges(String),
+}
+
+#[cfg(feature = "session")]
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionError::UnexpectedlyClosed =>
+                write!(f, "Connection unexpectedly closed"),
+            SessionError::Db(err) =>
+                write!(f, "Database error: {err}"),
+            SessionError::Conflict =>
+                write!(f, "Changes conflict with the current contents of the database"),
+            SessionError::InvalidChanges(msg) =>
+                write!(f, "Invalid changes: {msg}"),
+        }
+    }
+}
+
+#[cfg(feature = "session")]
+impl std::error::Error for SessionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SessionError::Db(err) => Some(err),
+            SessionError::UnexpectedlyClosed |
+            SessionError::Conflict |
+            SessionError::InvalidChanges(_) => None,
+        }
+    }
+}
+
+#[cfg(feature = "session")]
+impl From<rusqlite::Error> for SessionError {
+    fn from(value: rusqlite::Error) -> Self {
+        SessionError::Db(value)
+    }
+}
+
+#[cfg(feature = "session")]
+impl From<async_rusqlite::AlreadyClosed> for SessionError {
+    fn from(_value: async_rusqlite::AlreadyClosed) -> Self {
+        SessionError::UnexpectedlyClosed
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::{ BTreeMap, BTreeSet };
+use async_rusqlite::Connection;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use rusqlite::types::{ Value, ValueRef };
+use serde_json::{ Map, Value as Json };
+use crate::error::JsonError;
+use crate::schema::quote_ident;
+
+// The key of the object that blobs are wrapped in.
+const BLOB_TAG: &str = "$blob";
+
+/// Export the rows of the given tables in the `main` database as JSON, or of every
+/// table if no tables are given. The result is an object with a key for each table,
+/// whose value is an array of rows. Each row is an object mapping column names to
+/// values. Blobs are base64 encoded and wrapped in an object like `{ "$blob": "yv4=" }`,
+/// so that they can be told apart from text whatever the type of their column.
+///
+/// ```rust
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use sqliter::{ ConnectionBuilder, export_json };
+///
+/// let conn = ConnectionBuilder::new()
+///     .add_migration(1, |conn| conn.execute_batch("
+///         CREATE TABLE user (id INTEGER PRIMARY KEY, name TEXT, avatar BLOB);
+///         INSERT INTO user VALUES (1, 'James', X'CAFE');
+///     "))
+///     .open_in_memory()
+///     .await?;
+///
+/// let json = export_json(&conn, &["user"]).await?;
+/// assert_eq!(json, serde_json::json!({
+///     "user": [{ "id": 1, "name": "James", "avatar": { "$blob": "yv4=" } }]
+/// }));
+/// # Ok(())
+/// # }
+/// ```
+pub async fn export_json(conn: &Connection, tables: &[&str]) -> Result<Json, JsonError> {
+    let tables: Vec<String> = tables.iter().map(|&t| t.to_owned()).collect();
+    conn.call(move |conn| {
+        // Read everything from one snapshot of the database.
+        let tx = conn.transaction()?;
+        let tables = if tables.is_empty() { all_tables(&tx)? } else { tables };
+
+        let mut out = Map::new();
+        for table in tables {
+            let columns = table_columns(&tx, &table)?;
+            let names: Vec<_> = columns.iter().map(|c| quote_ident(&c.name)).collect();
+            let sql = format!("SELECT {} FROM {}", names.join(","), quote_ident(&table));
+
+            let mut stmt = tx.prepare(&sql)?;
+            let mut rows = stmt.query([])?;
+            let mut json_rows = Vec::new();
+            while let Some(row) = rows.next()? {
+                let mut json_row = Map::new();
+                for (idx, column) in columns.iter().enumerate() {
+                    json_row.insert(column.name.clone(), to_json(row.get_ref(idx)?));
+                }
+                json_rows.push(Json::Object(json_row));
+            }
+            out.insert(table, Json::Array(json_rows));
+        }
+        Ok(Json::Object(out))
+    }).await
+}
+
+/// Import rows from JSON in the format that [`export_json()`] produces. Everything
+/// is imported in a single transaction, so if anything fails then nothing changes.
+///
+/// - Every table and column is checked against `pragma_table_info`, and unknown
+///   ones are reported as errors.
+/// - Tables are filled in an order which puts rows into referenced tables before
+///   the tables referencing them. Foreign keys are checked once everything has been
+///   imported, so cycles are fine too.
+/// - Rows whose primary key already exists are updated, so an export can be edited
+///   and imported again.
+/// - Objects like `{ "$blob": "yv4=" }` are base64 decoded into blobs. Other arrays
+///   and objects are stored as JSON text.
+pub async fn import_json(conn: &Connection, json: Json) -> Result<(), JsonError> {
+    conn.call(move |conn| {
+        let Json::Object(tables) = json else {
+            return Err(JsonError::Invalid("expected an object mapping table names to rows".to_owned()))
+        };
+
+        let tx = conn.transaction()?;
+        // Foreign keys are checked on commit instead of after each insert.
+        tx.pragma_update(None, "defer_foreign_keys", true)?;
+
+        let mut columns = BTreeMap::new();
+        for table in tables.keys() {
+            columns.insert(table.as_str(), table_columns(&tx, table)?);
+        }
+
+        for table in foreign_key_order(&tx, &columns)? {
+            let Json::Array(rows) = &tables[table] else {
+                return Err(JsonError::Invalid(format!("expected an array of rows for table {table}")))
+            };
+            for row in rows {
+                insert_row(&tx, table, &columns[table], row)?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(())
+    }).await
+}
+
+struct ColumnInfo {
+    name: String,
+    is_pk: bool,
+}
+
+// The names of all of the ordinary tables in the main database.
+fn all_tables(conn: &rusqlite::Connection) -> Result<Vec<String>, rusqlite::Error> {
+    let mut stmt = conn.prepare("
+        SELECT name FROM pragma_table_list
+        WHERE schema = 'main' AND type = 'table' AND name NOT LIKE 'sqlite\\_%' ESCAPE '\\'
+        ORDER BY name
+    ")?;
+    let tables = stmt.query_map([], |row| row.get(0))?.collect();
+    tables
+}
+
+// The columns that can be read from and written to in a table, which must exist.
+fn table_columns(conn: &rusqlite::Connection, table: &str) -> Result<Vec<ColumnInfo>, JsonError> {
+    let mut stmt = conn.prepare("
+        SELECT name, pk > 0 FROM pragma_table_xinfo(?1, 'main')
+        WHERE hidden = 0 AND EXISTS (
+            SELECT 1 FROM sqlite_schema WHERE type = 'table' AND name = ?1
+        )
+    ")?;
+    let columns = stmt
+        .query_map([table], |row| Ok(ColumnInfo { name: row.get(0)?, is_pk: row.get(1)? }))?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if columns.is_empty() {
+        return Err(JsonError::UnknownTable(table.to_owned()))
+    }
+    Ok(columns)
+}
+
+// Order tables so that those referenced by foreign keys come before those that
+// reference them. Any tables left in a cycle are added in name order at the end.
+fn foreign_key_order<'a>(conn: &rusqlite::Connection, tables: &BTreeMap<&'a str, Vec<ColumnInfo>>) -> Result<Vec<&'a str>, rusqlite::Error> {
+    let mut stmt = conn.prepare(r#"SELECT DISTINCT "table" FROM pragma_foreign_key_list(?1, 'main')"#)?;
+    let mut parents = BTreeMap::new();
+    for &table in tables.keys() {
+        let referenced = stmt
+            .query_map([table], |row| row.get::<_, String>(0))?
+            .collect::<Result<BTreeSet<_>, _>>()?;
+        let referenced: BTreeSet<&str> = tables.keys()
+            .copied()
+            .filter(|&t| t != table && referenced.contains(t))
+            .collect();
+        parents.insert(table, referenced);
+    }
+
+    let mut order = Vec::new();
+    while !parents.is_empty() {
+        let ready: Vec<&str> = parents.iter()
+            .filter(|(_, p)| p.iter().all(|p| order.contains(p)))
+            .map(|(&t, _)| t)
+            .collect();
+        let next = if ready.is_empty() { parents.keys().copied().collect() } else { ready };
+        for table in next {
+            parents.remove(table);
+            order.push(table);
+        }
+    }
+    Ok(order)
+}
+
+// Insert a row into a table, or update it if its primary key already exists.
+fn insert_row(conn: &rusqlite::Connection, table: &str, columns: &[ColumnInfo], row: &Json) -> Result<(), JsonError> {
+    let Json::Object(row) = row else {
+        return Err(JsonError::Invalid(format!("expected each row of table {table} to be an object")))
+    };
+
+    let mut names = Vec::new();
+    let mut values = Vec::new();
+    let mut updates = Vec::new();
+    let mut pk_names = Vec::new();
+    for (name, value) in row {
+        let column = columns.iter().find(|c| &c.name == name).ok_or_else(|| {
+            JsonError::UnknownColumn { table: table.to_owned(), column: name.clone() }
+        })?;
+        let name = quote_ident(name);
+        if column.is_pk {
+            pk_names.push(name.clone());
+        } else {
+            updates.push(format!("{name} = excluded.{name}"));
+        }
+        values.push(from_json(value)?);
+        names.push(name);
+    }
+
+    if names.is_empty() {
+        conn.execute(&format!("INSERT INTO {} DEFAULT VALUES", quote_ident(table)), [])?;
+        return Ok(())
+    }
+
+    let placeholders = vec!["?"; names.len()].join(",");
+    let mut sql = format!("INSERT INTO {} ({}) VALUES ({placeholders})", quote_ident(table), names.join(","));
+    // Only rows which name their whole primary key can conflict with existing ones.
+    let pk_len = columns.iter().filter(|c| c.is_pk).count();
+    if pk_len > 0 && pk_names.len() == pk_len {
+        sql.push_str(&format!(" ON CONFLICT ({}) DO ", pk_names.join(",")));
+        if updates.is_empty() {
+            sql.push_str("NOTHING");
+        } else {
+            sql.push_str(&format!("UPDATE SET {}", updates.join(",")));
+        }
+    }
+
+    conn.prepare_cached(&sql)?.execute(rusqlite::params_from_iter(values))?;
+    Ok(())
+}
+
+fn to_json(value: ValueRef<'_>) -> Json {
+    match value {
+        ValueRef::Null => Json::Null,
+        ValueRef::Integer(n) => Json::from(n),
+        // JSON has no infinity, so that becomes null.
+        ValueRef::Real(f) => serde_json::Number::from_f64(f).map(Json::Number).unwrap_or(Json::Null),
+        ValueRef::Text(text) => Json::String(String::from_utf8_lossy(text).into_owned()),
+        ValueRef::Blob(blob) => {
+            let mut tagged = Map::new();
+            tagged.insert(BLOB_TAG.to_owned(), Json::String(BASE64.encode(blob)));
+            Json::Object(tagged)
+        },
+    }
+}
+
+fn from_json(value: &Json) -> Result<Value, JsonError> {
+    Ok(match value {
+        Json::Null => Value::Null,
+        Json::Bool(b) => Value::Integer(*b as i64),
+        Json::Number(n) => match (n.as_i64(), n.as_f64()) {
+            (Some(n), _) => Value::Integer(n),
+            (None, Some(f)) => Value::Real(f),
+            (None, None) => Value::Null,
+        },
+        Json::String(s) => Value::Text(s.clone()),
+        Json::Object(o) if o.len() == 1 && o.contains_key(BLOB_TAG) => {
+            let Json::String(s) = &o[BLOB_TAG] else {
+                return Err(JsonError::Invalid(format!("expected a base64 string for {BLOB_TAG}")))
+            };
+            let blob = BASE64.decode(s).map_err(|e| JsonError::Invalid(format!("bad base64 for blob: {e}")))?;
+            Value::Blob(blob)
+        },
+        // Store anything else as JSON text, for use with SQLite's JSON functions.
+        Json::Array(_) | Json::Object(_) => Value::Text(value.to_string()),
+    })
+}
@@ -41,13 +41,57 @@
 //! # }
 //! ```
 
+mod backup;
 mod builder;
+mod dump;
 mod error;
+mod maintenance;
 mod migrations;
+mod schema;
+mod serialize;
+mod signal;
+mod snapshots;
+mod stats;
+mod template;
+mod timer;
+#[cfg(feature = "sqlcipher")]
+mod sqlcipher;
+#[cfg(feature = "json")]
+mod json;
+#[cfg(feature = "csv")]
+mod csv;
+#[cfg(feature = "session")]
+mod replica;
+#[cfg(feature = "session")]
+mod session;
 
-pub use builder::ConnectionBuilder;
-pub use error::ConnectionBuilderError;
+pub use backup::{ BackupProgress, backup };
+pub use builder::{ ConnectionBuilder, IntegrityCheck, Opened, Quarantined };
+pub use dump::dump;
+pub use error::{ ConnectionBuilderError, DumpError, SnapshotError };
 pub use migrations::Migrations;
+pub use maintenance::{ Housekeeping, Maintenance, MaintenanceJob, MaintenanceTask };
+pub use serialize::serialize;
+pub use snapshots::{ Snapshot, Snapshots, list_snapshots };
+pub use stats::{ Stats, stats };
+pub use template::Template;
+pub use schema::{ Schema, SchemaDiff, Table, Column, Index, dump_schema, migration_sql };
+#[cfg(feature = "sqlcipher")]
+pub use sqlcipher::rekey;
+#[cfg(feature = "json")]
+pub use json::{ export_json, import_json };
+#[cfg(feature = "json")]
+pub use error::JsonError;
+#[cfg(feature = "csv")]
+pub use csv::{ CsvImport, export_csv };
+#[cfg(feature = "csv")]
+pub use error::CsvError;
+#[cfg(feature = "session")]
+pub use replica::{ Changes, Conflict, ConflictKind, Replica, Resolution };
+#[cfg(feature = "session")]
+pub use session::UndoStack;
+#[cfg(feature = "session")]
+pub use error::SessionError;
 
 // Export these since we are just a thin wrapper around them.
 pub use async_rusqlite::{ self, rusqlite, Connection };
@@ -313,4 +357,1565 @@ mod test {
             .unwrap();
         assert_eq!(name, "James");
     }
+
+    #[tokio::test]
+    async fn attached_db_is_migrated_separately() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("test-db1.app");
+        let cache_path = tempdir.path().join("test-db1.cache");
+
+        let cache_migrations = || Migrations::new().add(3, |conn| {
+            conn.execute_batch("
+                CREATE TABLE names (user INTEGER NOT NULL, name TEXT NOT NULL);
+                INSERT INTO names VALUES (1, 'Jimmy');
+            ")
+        });
+
+        let conn = ConnectionBuilder::new()
+            .app_id(1)
+            .add_migration(1, users_table)
+            // Attached databases are ready before the main migrations run:
+            .add_migration(2, |conn| conn.execute_batch("
+                INSERT INTO users SELECT 3, name FROM cache.names WHERE user = 1;
+            "))
+            .attach("cache", &cache_path, cache_migrations())
+            .open(&path)
+            .await
+            .unwrap();
+
+        // Each database tracks its own version:
+        assert_eq!(get_user_version(&conn).await, 2);
+        let cache_version: i32 = conn.call(|conn| {
+            conn.pragma_query_value(Some("cache"), "user_version", |row| row.get(0))
+        }).await.unwrap();
+        assert_eq!(cache_version, 3);
+
+        // The names table lives only in the attached database, but can be queried
+        // alongside the main one:
+        let name: String = conn.call(|conn| {
+            conn.query_row("
+                SELECT names.name FROM cache.names JOIN main.users ON names.user = users.id
+            ", [], |row| row.get(0))
+        }).await.unwrap();
+        assert_eq!(name, "Jimmy");
+        let copied: String = conn.call(|conn| {
+            conn.query_row("SELECT name FROM users WHERE id = 3", [], |row| row.get(0))
+        }).await.unwrap();
+        assert_eq!(copied, "Jimmy");
+        let in_main = conn.call(|conn| {
+            conn.query_row("SELECT count(*) FROM main.names", [], |_| Ok(()))
+        }).await;
+        assert!(in_main.is_err());
+        drop(conn);
+
+        // A different app ID on the attached database is caught:
+        let conn = ConnectionBuilder::new()
+            .app_id(2)
+            .attach("cache", &cache_path, cache_migrations())
+            .open_in_memory()
+            .await;
+
+        assert!(
+            matches!(conn, Err(ConnectionBuilderError::Attached { error, .. }) if matches!(*error, ConnectionBuilderError::WrongApplicationId(1)))
+        );
+    }
+
+    #[tokio::test]
+    async fn safe_defaults_reject_double_quoted_strings() {
+        async fn query(conn: &Connection) -> Result<String, rusqlite::Error> {
+            conn.call(|conn| {
+                conn.query_row(r#"SELECT "name" FROM users WHERE id = 1"#, [], |row| row.get(0))
+            }).await
+        }
+
+        // Without safe defaults, "name" is the column:
+        let conn = ConnectionBuilder::new()
+            .add_migration(1, users_table)
+            .open_in_memory()
+            .await
+            .unwrap();
+        assert_eq!(query(&conn).await.unwrap(), "James");
+
+        // And if the column doesn't exist, it's quietly treated as a string:
+        let conn = ConnectionBuilder::new()
+            .add_migration(1, |conn| conn.execute_batch("
+                CREATE TABLE users (id INTEGER PRIMARY KEY NOT NULL);
+                INSERT INTO users VALUES (1);
+            "))
+            .open_in_memory()
+            .await
+            .unwrap();
+        assert_eq!(query(&conn).await.unwrap(), "name");
+
+        // With safe defaults, that's an error:
+        let conn = ConnectionBuilder::new()
+            .safe_defaults()
+            .add_migration(1, |conn| conn.execute_batch("
+                CREATE TABLE users (id INTEGER PRIMARY KEY NOT NULL);
+                INSERT INTO users VALUES (1);
+            "))
+            .open_in_memory()
+            .await
+            .unwrap();
+        assert!(query(&conn).await.is_err());
+
+        // Writing to the schema directly is also prevented:
+        let res = conn.call(|conn| {
+            conn.execute_batch("
+                PRAGMA writable_schema = ON;
+                UPDATE sqlite_schema SET sql = 'garbage' WHERE name = 'users';
+            ")
+        }).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn corruption_is_detected() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("test-db1.app");
+
+        let conn = ConnectionBuilder::new()
+            .add_migration(1, users_table)
+            .add_migration(2, |conn| conn.execute_batch("CREATE INDEX users_name ON users(name)"))
+            .check_integrity(IntegrityCheck::Full)
+            .open(&path)
+            .await
+            .unwrap();
+
+        // Corrupt the database by making the index disagree with its contents:
+        conn.call(|conn| {
+            conn.execute_batch("
+                PRAGMA writable_schema = ON;
+                UPDATE sqlite_schema SET sql = 'CREATE INDEX users_name ON users(id)' WHERE name = 'users_name';
+            ")
+        }).await.unwrap();
+        drop(conn);
+
+        // A quick check doesn't look at indexes, so won't spot this:
+        let conn = ConnectionBuilder::new()
+            .add_migration(1, users_table)
+            .add_migration(2, |conn| conn.execute_batch("CREATE INDEX users_name ON users(name)"))
+            .check_integrity(IntegrityCheck::Quick)
+            .open(&path)
+            .await;
+        assert!(conn.is_ok());
+        drop(conn);
+
+        // But a full check will:
+        let conn = ConnectionBuilder::new()
+            .add_migration(1, users_table)
+            .add_migration(2, |conn| conn.execute_batch("CREATE INDEX users_name ON users(name)"))
+            .check_integrity(IntegrityCheck::Full)
+            .open(&path)
+            .await;
+        assert!(
+            matches!(conn, Err(ConnectionBuilderError::Corrupt(problems)) if !problems.is_empty())
+        );
+    }
+
+    #[tokio::test]
+    async fn corrupt_db_can_be_quarantined() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("test-db1.app");
+        std::fs::write(&path, "definitely not a database file, but long enough to look like one maybe").unwrap();
+
+        // By default, we just get an error back:
+        let conn = ConnectionBuilder::new()
+            .add_migration(1, users_table)
+            .open(&path)
+            .await;
+        assert!(
+            matches!(conn, Err(ConnectionBuilderError::Db(_)))
+        );
+
+        // But we can ask for corrupt databases to be moved aside:
+        let opened = ConnectionBuilder::new()
+            .add_migration(1, users_table)
+            .quarantine_corrupt()
+            .open_detailed(&path)
+            .await
+            .unwrap();
+
+        let quarantined = opened.quarantined.expect("db should have been quarantined");
+        assert!(quarantined.path.file_name().unwrap().to_str().unwrap().starts_with("test-db1.app.corrupt-"));
+        assert!(std::fs::read_to_string(&quarantined.path).unwrap().starts_with("definitely not a database"));
+
+        // And a fresh database is created in its place:
+        assert_eq!(get_user_version(&opened.connection).await, 1);
+        drop(opened.connection);
+
+        // Which won't be quarantined next time, since it's fine:
+        let opened = ConnectionBuilder::new()
+            .add_migration(1, users_table)
+            .quarantine_corrupt()
+            .open_detailed(&path)
+            .await
+            .unwrap();
+        assert!(opened.quarantined.is_none());
+        drop(opened.connection);
+
+        // Quarantining again never overwrites an earlier quarantined database:
+        for n in 0..3 {
+            std::fs::write(&path, format!("not a database either, attempt {n}, but long enough to look like one")).unwrap();
+            let opened = ConnectionBuilder::new()
+                .add_migration(1, users_table)
+                .quarantine_corrupt()
+                .open_detailed(&path)
+                .await
+                .unwrap();
+            assert!(opened.quarantined.is_some());
+        }
+        assert!(std::fs::read_to_string(&quarantined.path).unwrap().starts_with("definitely not a database"));
+        let corrupt = std::fs::read_dir(tempdir.path()).unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_str().unwrap().starts_with("test-db1.app.corrupt-"))
+            .count();
+        assert_eq!(corrupt, 4);
+    }
+
+    #[tokio::test]
+    async fn schema_drift_is_detected() {
+        let expected = Schema::from_sql("
+            CREATE TABLE users (
+                id INTEGER PRIMARY KEY NOT NULL,
+                name TEXT NOT NULL
+            ) STRICT;
+            CREATE INDEX users_name ON users (name);
+        ").unwrap();
+
+        let add_index = |conn: &rusqlite::Connection| {
+            conn.execute_batch("CREATE INDEX users_name ON users(name)")
+        };
+
+        // Matches, ignoring whitespace differences:
+        let conn = ConnectionBuilder::new()
+            .add_migration(1, users_table)
+            .add_migration(2, add_index)
+            .verify_schema(expected.clone())
+            .open_in_memory()
+            .await;
+        assert!(conn.is_ok());
+
+        // Doesn't match:
+        let conn = ConnectionBuilder::new()
+            .add_migration(1, users_table)
+            .add_migration(2, data_table)
+            .add_migration(3, |conn| conn.execute_batch("ALTER TABLE users ADD COLUMN age INTEGER"))
+            .verify_schema(expected)
+            .open_in_memory()
+            .await;
+
+        let Err(ConnectionBuilderError::SchemaDrift(diff)) = conn else {
+            panic!("expected schema drift")
+        };
+        assert_eq!(diff.extra_tables, vec!["data".to_owned()]);
+        assert_eq!(diff.extra_columns, vec![("users".to_owned(), "age".to_owned())]);
+        assert_eq!(diff.missing_indexes, vec!["users_name".to_owned()]);
+        assert!(diff.missing_tables.is_empty());
+        assert!(diff.missing_columns.is_empty());
+    }
+
+    #[test]
+    fn schema_comparison_ignores_case_and_quoting() {
+        let expected = Schema::from_sql("
+            CREATE TABLE users (id INTEGER PRIMARY KEY, b TEXT, c TEXT DEFAULT 'Hi') STRICT;
+            CREATE INDEX users_b ON users (b);
+        ").unwrap();
+
+        // Lowercase keywords:
+        let actual = Schema::from_sql("
+            create table users (id integer primary key, b text, c text default 'Hi') strict;
+            create index users_b on users (b);
+        ").unwrap();
+        assert!(actual.diff(&expected).is_empty(), "{:?}", actual.diff(&expected));
+
+        // Identifiers in a different case:
+        let actual = Schema::from_sql("
+            CREATE TABLE Users (ID INTEGER PRIMARY KEY, B TEXT, C TEXT DEFAULT 'Hi') STRICT;
+            CREATE INDEX USERS_B ON USERS (B);
+        ").unwrap();
+        assert!(actual.diff(&expected).is_empty(), "{:?}", actual.diff(&expected));
+
+        // Quoted identifiers:
+        let actual = Schema::from_sql("
+            CREATE TABLE \"users\" (\"id\" INTEGER PRIMARY KEY, [b] TEXT, `c` TEXT DEFAULT 'Hi') STRICT;
+            CREATE INDEX [users_b] ON `users` (\"b\");
+        ").unwrap();
+        assert!(actual.diff(&expected).is_empty(), "{:?}", actual.diff(&expected));
+
+        // But the case of string literals matters:
+        let actual = Schema::from_sql("
+            CREATE TABLE users (id INTEGER PRIMARY KEY, b TEXT, c TEXT DEFAULT 'HI') STRICT;
+            CREATE INDEX users_b ON users (b);
+        ").unwrap();
+        assert_eq!(actual.diff(&expected).changed_columns, vec![("users".to_owned(), "c".to_owned())]);
+    }
+
+    #[tokio::test]
+    async fn schema_dump_is_normalized() {
+        let conn = ConnectionBuilder::new()
+            .app_id(1337)
+            .add_migration(1, users_table)
+            .add_migration(2, data_table)
+            .add_migration(3, |conn| conn.execute_batch("
+                CREATE VIEW   user_data AS SELECT users.name, data.text
+                    FROM users JOIN data ON data.owner = users.id;
+                CREATE INDEX data_owner ON data ( owner );
+                CREATE TABLE sqlites (
+                    id INTEGER PRIMARY KEY, -- not an internal table
+                    /* a block
+                       comment */ name TEXT
+                );
+                CREATE TRIGGER users_delete AFTER DELETE ON users BEGIN
+                    DELETE FROM data WHERE owner = old.id;
+                END;
+            "))
+            .open_in_memory()
+            .await
+            .unwrap();
+
+        let dump = conn.call(|conn| dump_schema(conn)).await.unwrap();
+        assert_eq!(dump, "\
+PRAGMA application_id = 1337;
+PRAGMA user_version = 3;
+
+CREATE TABLE data(owner INTEGER NOT NULL,text TEXT NOT NULL,FOREIGN KEY(owner) REFERENCES users(id)) STRICT;
+CREATE TABLE sqlites(id INTEGER PRIMARY KEY,name TEXT);
+CREATE TABLE users(id INTEGER PRIMARY KEY NOT NULL,name TEXT NOT NULL) STRICT;
+
+CREATE INDEX data_owner ON data(owner);
+
+CREATE TRIGGER users_delete AFTER DELETE ON users BEGIN DELETE FROM data WHERE owner = old.id; END;
+
+CREATE VIEW user_data AS SELECT users.name,data.text FROM users JOIN data ON data.owner = users.id;
+");
+        // The dump can be used to recreate the schema.
+        rusqlite::Connection::open_in_memory().unwrap().execute_batch(&dump).unwrap();
+    }
+
+    #[tokio::test]
+    async fn declared_schema_is_applied() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("test-db1.app");
+
+        let v1 = "
+            CREATE TABLE users (
+                id INTEGER PRIMARY KEY NOT NULL, -- comments are fine
+                name TEXT NOT NULL
+            ) STRICT;
+            CREATE TABLE old (id INTEGER PRIMARY KEY NOT NULL);
+        ";
+        let v2 = "
+            CREATE TABLE users (
+                id INTEGER PRIMARY KEY NOT NULL,
+                name TEXT NOT NULL,
+                age INTEGER CHECK (age > 0),
+                \"favourite, colour\" TEXT NOT NULL DEFAULT 'blue'
+            ) STRICT;
+            CREATE TABLE data (
+                owner INTEGER NOT NULL REFERENCES users(id), -- who the data belongs to
+                text TEXT NOT NULL
+            ) STRICT;
+            CREATE INDEX users_name ON users (name);
+        ";
+
+        let conn = ConnectionBuilder::<rusqlite::Error>::new()
+            .schema(v1)
+            .open(&path)
+            .await
+            .unwrap();
+        conn.call(|conn| conn.execute("INSERT INTO users VALUES (1, 'James')", ())).await.unwrap();
+        // A new database is given the schema exactly as written:
+        let sql: String = conn.call(|conn| {
+            conn.query_row("SELECT sql FROM sqlite_schema WHERE name = 'users'", [], |row| row.get(0))
+        }).await.unwrap();
+        assert!(sql.contains("-- comments are fine"));
+        drop(conn);
+
+        // Dropping the "old" table is destructive, so isn't allowed by default:
+        let conn = ConnectionBuilder::<rusqlite::Error>::new()
+            .schema(v2)
+            .open(&path)
+            .await;
+        let Err(ConnectionBuilderError::DestructiveSchemaChange(diff)) = conn else {
+            panic!("expected destructive change error")
+        };
+        assert_eq!(diff.extra_tables, vec!["old".to_owned()]);
+
+        // Until we allow it:
+        let conn = ConnectionBuilder::<rusqlite::Error>::new()
+            .schema(v2)
+            .allow_destructive_schema_changes()
+            .verify_schema(Schema::from_sql(v2).unwrap())
+            .open(&path)
+            .await
+            .unwrap();
+
+        // Existing data is kept, and new columns have their defaults and constraints:
+        let colour: String = conn.call(|conn| {
+            conn.query_row(r#"SELECT "favourite, colour" FROM users WHERE name = 'James'"#, [], |row| row.get(0))
+        }).await.unwrap();
+        assert_eq!(colour, "blue");
+        let bad_age = conn.call(|conn| conn.execute("UPDATE users SET age = -1", ())).await;
+        assert!(bad_age.is_err());
+        let bad_owner = conn.call(|conn| conn.execute("INSERT INTO data VALUES (2, 'Nope')", ())).await;
+        assert!(bad_owner.is_err());
+        conn.call(|conn| conn.execute("INSERT INTO data VALUES (1, 'James data')", ())).await.unwrap();
+        drop(conn);
+
+        // Changing a column rebuilds the table, leaving data (and rows referencing it) intact:
+        let v3 = v2.replace("name TEXT NOT NULL,", "name TEXT NOT NULL UNIQUE,");
+        let conn = ConnectionBuilder::<rusqlite::Error>::new()
+            .schema(v3.clone())
+            .allow_destructive_schema_changes()
+            .verify_schema(Schema::from_sql(&v3).unwrap())
+            .open(&path)
+            .await
+            .unwrap();
+
+        let text: String = conn.call(|conn| {
+            conn.query_row("SELECT text FROM data JOIN users ON data.owner = users.id", [], |row| row.get(0))
+        }).await.unwrap();
+        assert_eq!(text, "James data");
+        let duplicate = conn.call(|conn| conn.execute("INSERT INTO users (id, name) VALUES (2, 'James')", ())).await;
+        assert!(duplicate.is_err());
+        drop(conn);
+
+        // Differences in case aren't changes, and views and triggers are created:
+        let v4 = v3.replace("name TEXT", "NAME TEXT") + "
+            CREATE VIEW names AS SELECT name FROM users;
+            CREATE TRIGGER users_delete AFTER DELETE ON users BEGIN
+                DELETE FROM data WHERE owner = old.id;
+            END;
+        ";
+        let conn = ConnectionBuilder::<rusqlite::Error>::new()
+            .schema(v4.clone())
+            .open(&path)
+            .await
+            .unwrap();
+        let names: String = conn.call(|conn| conn.query_row("SELECT name FROM names", [], |row| row.get(0))).await.unwrap();
+        assert_eq!(names, "James");
+        drop(conn);
+
+        // And changed or removed when they are:
+        let v5 = v4
+            .replace("SELECT name FROM users", "SELECT upper(name) AS name FROM users")
+            .replace("DELETE FROM data WHERE owner = old.id;", "SELECT 1;");
+        let conn = ConnectionBuilder::<rusqlite::Error>::new()
+            .schema(v5.clone())
+            .verify_schema(Schema::from_sql(&v5).unwrap())
+            .open(&path)
+            .await
+            .unwrap();
+        let names: String = conn.call(|conn| conn.query_row("SELECT name FROM names", [], |row| row.get(0))).await.unwrap();
+        assert_eq!(names, "JAMES");
+        conn.call(|conn| conn.execute("DELETE FROM users", ())).await.unwrap_err();
+        drop(conn);
+        let conn = ConnectionBuilder::<rusqlite::Error>::new()
+            .schema(v3.clone())
+            .open(&path)
+            .await
+            .unwrap();
+        let dependents: i32 = conn.call(|conn| {
+            conn.query_row("SELECT count(*) FROM sqlite_schema WHERE type IN ('view', 'trigger')", [], |row| row.get(0))
+        }).await.unwrap();
+        assert_eq!(dependents, 0);
+        drop(conn);
+
+        // Tables from migrations would look like extra tables, so the two can't be mixed:
+        let conn = ConnectionBuilder::new()
+            .add_migration(1, data_table)
+            .schema(v3)
+            .open(&path)
+            .await;
+        assert!(matches!(conn, Err(ConnectionBuilderError::SchemaWithMigrations)));
+    }
+
+    #[test]
+    fn migration_sql_rebuilds_changed_tables() {
+        let from = rusqlite::Connection::open_in_memory().unwrap();
+        users_table(&from).unwrap();
+        data_table(&from).unwrap();
+        from.execute_batch("
+            CREATE INDEX data_text ON data(text);
+            CREATE VIEW user_names AS SELECT name FROM users;
+            CREATE TRIGGER users_delete AFTER DELETE ON users BEGIN
+                DELETE FROM data WHERE owner = old.id;
+            END;
+        ").unwrap();
+
+        let to = rusqlite::Connection::open_in_memory().unwrap();
+        to.execute_batch("
+            CREATE TABLE users (
+                id INTEGER PRIMARY KEY NOT NULL,
+                name TEXT NOT NULL UNIQUE, -- names must be unique
+                email TEXT /* optional */
+            ) STRICT;
+            CREATE TABLE data (
+                owner INTEGER NOT NULL,
+                text TEXT NOT NULL,
+                FOREIGN KEY(owner) REFERENCES users(id)
+            ) STRICT;
+            CREATE INDEX data_text ON data(text);
+            CREATE INDEX users_email ON users(email);
+            CREATE VIEW user_names AS SELECT name FROM users;
+            CREATE TRIGGER users_delete AFTER DELETE ON users BEGIN
+                DELETE FROM data WHERE owner = old.id;
+            END;
+        ").unwrap();
+
+        let sql = migration_sql(&from, &to).unwrap();
+        assert_eq!(sql, "\
+-- This migration rebuilds tables, so foreign key enforcement must be disabled
+-- while it runs; see `Migrations::add_non_transactionally()`.
+DROP TRIGGER IF EXISTS \"users_delete\";
+DROP VIEW IF EXISTS \"user_names\";
+CREATE TABLE \"users_sqliter_new\"(id INTEGER PRIMARY KEY NOT NULL,name TEXT NOT NULL UNIQUE,email TEXT) STRICT;
+INSERT INTO \"users_sqliter_new\"(\"id\",\"name\") SELECT \"id\",\"name\" FROM \"users\";
+DROP TABLE \"users\";
+ALTER TABLE \"users_sqliter_new\" RENAME TO \"users\";
+CREATE INDEX users_email ON users(email);
+CREATE VIEW user_names AS SELECT name FROM users;
+CREATE TRIGGER users_delete AFTER DELETE ON users BEGIN DELETE FROM data WHERE owner = old.id; END;
+");
+
+        // Applying the SQL gets us to the desired schema, keeping the data:
+        from.pragma_update(None, "foreign_keys", false).unwrap();
+        from.execute_batch(&sql).unwrap();
+        assert_eq!(Schema::read(&from).unwrap().diff(&Schema::read(&to).unwrap()), SchemaDiff::default());
+        let count: i32 = from.query_row("SELECT count(*) FROM users JOIN data ON data.owner = users.id", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 1);
+
+        // And the view and trigger on the rebuilt table still work:
+        let names: i32 = from.query_row("SELECT count(*) FROM user_names", [], |row| row.get(0)).unwrap();
+        assert_eq!(names, 2);
+        from.execute("DELETE FROM users WHERE id = 1", []).unwrap();
+        let count: i32 = from.query_row("SELECT count(*) FROM data", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[tokio::test]
+    async fn stats_are_reported() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("test-db1.app");
+
+        let conn = ConnectionBuilder::new()
+            .app_id(1337)
+            .add_migration(1, users_table)
+            .add_migration_non_transactionally(2, |conn| conn.pragma_update(None, "journal_mode", "wal"))
+            .open(&path)
+            .await
+            .unwrap();
+
+        let info = stats(&conn).await.unwrap();
+        assert_eq!(info.application_id, 1337);
+        assert_eq!(info.user_version, 2);
+        assert_eq!(info.journal_mode, "wal");
+        assert_eq!(info.sqlite_version, rusqlite::version());
+        assert!(info.page_count > 0);
+        assert!(info.wal_size.unwrap() > 0);
+        assert_eq!(info.file_size, Some(std::fs::metadata(&path).unwrap().len()));
+
+        // In-memory databases have no files:
+        let conn = ConnectionBuilder::new()
+            .add_migration(1, users_table)
+            .open_in_memory()
+            .await
+            .unwrap();
+
+        let info = stats(&conn).await.unwrap();
+        assert_eq!(info.file_size, None);
+        assert_eq!(info.wal_size, None);
+        assert_eq!(info.journal_mode, "memory");
+    }
+
+    #[tokio::test]
+    async fn sleeps_wake_in_deadline_order() {
+        use std::sync::{ Arc, Mutex };
+        use std::time::Duration;
+
+        let woken = Arc::new(Mutex::new(Vec::new()));
+        let mut tasks = Vec::new();
+        for ms in [30, 10, 20, 0] {
+            let woken = woken.clone();
+            tasks.push(tokio::spawn(async move {
+                timer::sleep(Duration::from_millis(ms)).await;
+                woken.lock().unwrap().push(ms);
+            }));
+        }
+        // Dropped sleeps are forgotten about, and don't hold up the others:
+        tokio::select! {
+            _ = timer::sleep(Duration::from_secs(60)) => {},
+            _ = timer::sleep(Duration::from_millis(1)) => {},
+        }
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(*woken.lock().unwrap(), vec![0, 10, 20, 30]);
+    }
+
+    #[tokio::test]
+    async fn maintenance_jobs_run_until_closed() {
+        use std::sync::{ Arc, Mutex };
+        use std::time::Duration;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("test-db1.app");
+
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let results2 = results.clone();
+
+        let opened = ConnectionBuilder::new()
+            .add_migration(1, users_table)
+            .maintenance(
+                Maintenance::new()
+                    .optimize_every(Duration::from_millis(10))
+                    .checkpoint_every(Duration::from_millis(15))
+                    .incremental_vacuum_every(Duration::from_millis(20), Some(10))
+                    .on_result(move |job, res| results2.lock().unwrap().push((job, res.is_ok())))
+            )
+            .open_detailed(&path)
+            .await
+            .unwrap();
+
+        let task = tokio::spawn(opened.maintenance.unwrap());
+        timer::sleep(Duration::from_millis(100)).await;
+
+        // Each job should have run successfully at least once:
+        for job in [MaintenanceJob::Optimize, MaintenanceJob::Checkpoint, MaintenanceJob::IncrementalVacuum] {
+            assert!(results.lock().unwrap().contains(&(job, true)), "{job:?} should have run");
+        }
+
+        // The task ends once the connection is closed:
+        opened.connection.close().await.unwrap();
+        tokio::select! {
+            res = task => res.unwrap(),
+            _ = timer::sleep(Duration::from_secs(1)) => panic!("maintenance task should have ended"),
+        }
+    }
+
+    #[tokio::test]
+    async fn close_stops_spawned_tasks() {
+        use std::sync::{ Arc, Mutex };
+        use std::time::Duration;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("test-db1.app");
+        let closed_with_conn = Arc::new(Mutex::new(None));
+        let closed_with_conn2 = closed_with_conn.clone();
+
+        let mut opened = ConnectionBuilder::new()
+            .add_migration(1, users_table)
+            .maintenance(Maintenance::new().optimize_every(Duration::from_millis(10)))
+            .snapshots(Snapshots::new(tempdir.path().join("snapshots")).every(Duration::from_secs(60)))
+            .on_close(move |conn| *closed_with_conn2.lock().unwrap() = Some(conn.is_some()))
+            .open_detailed(&path)
+            .await
+            .unwrap();
+
+        let maintenance = tokio::spawn(opened.maintenance.take().unwrap());
+        let snapshots = tokio::spawn(opened.snapshots.take().unwrap());
+        timer::sleep(Duration::from_millis(20)).await;
+
+        // The spawned tasks hold connection clones, but don't stop it closing:
+        tokio::select! {
+            _ = opened.close() => {},
+            _ = timer::sleep(Duration::from_secs(1)) => panic!("close should not hang"),
+        }
+        maintenance.await.unwrap();
+        snapshots.await.unwrap();
+        assert_eq!(*closed_with_conn.lock().unwrap(), Some(true));
+    }
+
+    #[tokio::test]
+    async fn housekeeping_runs_before_on_close() {
+        use std::sync::{ Arc, Mutex };
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("test-db1.app");
+
+        // Note: the file doesn't exist yet, so on_close must survive the first open attempt.
+        let seen = Arc::new(Mutex::new(None));
+        let seen2 = seen.clone();
+
+        let opened = ConnectionBuilder::new()
+            .add_migration_non_transactionally(1, |conn| {
+                conn.execute_batch("
+                    PRAGMA journal_mode = WAL;
+                    CREATE TABLE blobs (data BLOB);
+                    WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 100)
+                    INSERT INTO blobs SELECT zeroblob(4096) FROM n;
+                    DELETE FROM blobs;
+                ")
+            })
+            .housekeeping(Housekeeping::new().optimize().checkpoint().vacuum_if_freelist_above(10))
+            .on_close(move |conn| {
+                let conn = conn.unwrap();
+                let freelist: u64 = conn.pragma_query_value(None, "freelist_count", |r| r.get(0)).unwrap();
+                *seen2.lock().unwrap() = Some(freelist);
+            })
+            .open_detailed(&path)
+            .await
+            .unwrap();
+
+        let before = stats(&opened.connection).await.unwrap();
+        assert!(before.freelist_count > 10);
+
+        opened.close().await;
+
+        // Housekeeping vacuumed before on_close ran, and close() waited for both:
+        assert_eq!(*seen.lock().unwrap(), Some(0));
+        let wal_size = std::fs::metadata(path.with_extension("app-wal")).map(|m| m.len()).unwrap_or(0);
+        assert_eq!(wal_size, 0);
+    }
+
+    #[tokio::test]
+    async fn max_size_reports_disk_full() {
+        fn fill(conn: &rusqlite::Connection) -> Result<(), rusqlite::Error> {
+            conn.execute_batch("
+                CREATE TABLE IF NOT EXISTS blobs (data BLOB);
+                WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 100)
+                INSERT INTO blobs SELECT zeroblob(4096) FROM n;
+            ")
+        }
+
+        // 400KiB of blobs won't fit in 64KiB:
+        let res = ConnectionBuilder::new()
+            .max_size(64 * 1024)
+            .add_migration(1, fill)
+            .open_in_memory()
+            .await;
+        assert!(matches!(res, Err(ConnectionBuilderError::DiskFull(_))), "{res:?}");
+
+        // But they will fit in 1MiB, and the limit sticks around after opening:
+        let conn = ConnectionBuilder::new()
+            .max_size(1024 * 1024)
+            .add_migration(1, fill)
+            .open_in_memory()
+            .await
+            .unwrap();
+        let res = conn.call(|conn| { fill(conn)?; fill(conn) }).await;
+        let code = res.unwrap_err().sqlite_error_code();
+        assert_eq!(code, Some(rusqlite::ErrorCode::DiskFull));
+    }
+
+    #[tokio::test]
+    async fn backup_copies_live_db() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("test-db1.app");
+        let backup_path = tempdir.path().join("test-db1.backup");
+
+        let conn = ConnectionBuilder::new()
+            .add_migration(1, users_table)
+            .add_migration(2, |conn| conn.execute_batch("
+                CREATE TABLE blobs (data BLOB);
+                WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+                INSERT INTO blobs SELECT zeroblob(4096) FROM n;
+            "))
+            .open(&path)
+            .await
+            .unwrap();
+
+        let mut steps = Vec::new();
+        backup(&conn, &backup_path, |p| steps.push(p)).await.unwrap();
+
+        // Several steps were needed, and the last one left nothing remaining:
+        assert!(steps.len() > 1);
+        let last = steps.last().unwrap();
+        assert_eq!(last.remaining, 0);
+        assert_eq!(last.page_count, stats(&conn).await.unwrap().page_count as u32);
+
+        // The connection is still usable, and the backup has everything in it:
+        let backup = Connection::open(&backup_path).await.unwrap();
+        assert_eq!(get_user_version(&backup).await, 2);
+        for conn in [&conn, &backup] {
+            let n: i64 = conn.call(|conn| conn.query_row("SELECT count(*) FROM blobs", [], |r| r.get(0))).await.unwrap();
+            assert_eq!(n, 500);
+        }
+    }
+
+    #[tokio::test]
+    async fn cancelled_backup_is_cleaned_up() {
+        use std::time::Duration;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("test-db1.app");
+        let backup_path = tempdir.path().join("test-db1.backup");
+        let conn = ConnectionBuilder::new()
+            .add_migration(1, users_table)
+            .open(&path)
+            .await
+            .unwrap();
+
+        // Lock the database, so that the backup waits between attempts to copy it,
+        // and then give up on it:
+        conn.call(|conn| conn.busy_timeout(Duration::ZERO)).await.unwrap();
+        let other = rusqlite::Connection::open(&path).unwrap();
+        other.execute_batch("BEGIN EXCLUSIVE").unwrap();
+        tokio::select! {
+            _ = backup(&conn, &backup_path, |_| {}) => panic!("backup should not finish while the database is locked"),
+            _ = timer::sleep(Duration::from_millis(50)) => {},
+        }
+        other.execute_batch("COMMIT").unwrap();
+        drop(other);
+
+        // The abandoned backup is finished on the connection thread, leaving the
+        // connection free to back up as usual:
+        backup(&conn, &backup_path, |_| {}).await.unwrap();
+
+        // Closing the connection explicitly fails while a backup is in progress, rather
+        // than leaving it unfinished, and works once the backup has been abandoned:
+        let other = rusqlite::Connection::open(&path).unwrap();
+        other.execute_batch("BEGIN EXCLUSIVE").unwrap();
+        let (c, p) = (conn.clone(), backup_path.clone());
+        let task = tokio::spawn(async move { backup(&c, &p, |_| {}).await });
+        timer::sleep(Duration::from_millis(50)).await;
+        assert!(matches!(conn.close().await, Err(async_rusqlite::Error::Rusqlite(_))));
+        task.abort();
+        assert!(task.await.unwrap_err().is_cancelled());
+        other.execute_batch("COMMIT").unwrap();
+        drop(other);
+        conn.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn restore_is_validated_and_migrated() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("test-db1.app");
+        let backup_path = tempdir.path().join("test-db1.backup");
+
+        const APP_ID: i32 = 1234;
+        let builder = || ConnectionBuilder::new()
+            .app_id(APP_ID)
+            .add_migration(1, users_table);
+
+        // A backup at version 1, and a target in WAL mode whose changes haven't been checkpointed:
+        let conn = builder().open(&backup_path).await.unwrap();
+        drop(conn);
+
+        let conn = builder()
+            .add_migration(2, data_table)
+            .open(&path)
+            .await
+            .unwrap();
+        conn.call(|conn| {
+            conn.pragma_update(None, "journal_mode", "WAL")?;
+            conn.pragma_update(None, "wal_autocheckpoint", 0)?;
+            conn.execute("INSERT INTO users VALUES (3, 'Wally')", [])
+        }).await.unwrap();
+        // Closing checkpoints and removes the WAL, so put a copy of it back afterwards
+        // as if the connection hadn't been closed cleanly:
+        let wal_path = tempdir.path().join("test-db1.app-wal");
+        let wal = std::fs::read(&wal_path).unwrap();
+        conn.close().await.unwrap();
+        std::fs::write(&wal_path, wal).unwrap();
+
+        // Backups for other apps, or from newer versions, are refused:
+        let res = ConnectionBuilder::new()
+            .add_migration(1, users_table)
+            .restore_from(&backup_path, &path)
+            .await;
+        assert!(matches!(res, Err(ConnectionBuilderError::WrongApplicationId(APP_ID))));
+
+        let res = ConnectionBuilder::<rusqlite::Error>::new()
+            .app_id(APP_ID)
+            .restore_from(&backup_path, &path)
+            .await;
+        assert!(matches!(res, Err(ConnectionBuilderError::OutOfDate { db_version: 1, latest_migration: 0 })));
+
+        // Valid backups replace the target, and are migrated. Any journal is removed too:
+        let journal_path = tempdir.path().join("test-db1.app-journal");
+        std::fs::write(&journal_path, b"left over from the old database").unwrap();
+        let conn = builder()
+            .add_migration(2, data_table)
+            .restore_from(&backup_path, &path)
+            .await
+            .unwrap();
+        assert_eq!(get_user_version(&conn).await, 2);
+        let names: Vec<String> = conn.call(|conn| {
+            conn.prepare("SELECT name FROM users ORDER BY id")?
+                .query_map([], |row| row.get(0))?
+                .collect()
+        }).await.unwrap();
+        assert_eq!(names, vec!["James", "Bob"]);
+        assert!(!journal_path.exists());
+        assert!(!wal_path.exists());
+    }
+
+    #[tokio::test]
+    async fn dump_round_trips() {
+        let conn = ConnectionBuilder::new()
+            .app_id(1234)
+            .add_migration(3, |conn| conn.execute_batch(r#"
+                CREATE TABLE "odd ""name""" (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    value BLOB,
+                    doubled ANY GENERATED ALWAYS AS (value || value)
+                );
+                CREATE INDEX odd_value ON "odd ""name"""(value);
+                CREATE INDEX sqlite1_value ON "odd ""name"""(value, id);
+                CREATE VIEW odd_view AS SELECT value FROM "odd ""name""";
+                CREATE TRIGGER odd_trigger AFTER DELETE ON "odd ""name""" BEGIN SELECT 1; END;
+
+                INSERT INTO "odd ""name""" (value) VALUES
+                    (NULL), (42), (-7), (0.1), (1.0), (1e300), (9e999), (-9e999),
+                    ('it''s'), ('line
+                    break'), (X'00FF10'), (X'');
+                DELETE FROM "odd ""name""" WHERE id = 1;
+            "#))
+            .open_in_memory()
+            .await
+            .unwrap();
+
+        let sql = String::from_utf8(dump(&conn, Vec::new()).await.unwrap()).unwrap();
+        assert!(sql.contains("INSERT INTO \"odd \"\"name\"\"\"(\"id\",\"value\") VALUES(9,'it''s');"), "{sql}");
+        assert!(sql.contains("VALUES(4,0.1);"));
+        assert!(sql.contains("VALUES(5,1.0);"));
+        assert!(sql.contains("VALUES(7,1e999);"));
+        assert!(sql.contains("VALUES(11,X'00FF10');"));
+        assert!(sql.contains("CREATE INDEX sqlite1_value"));
+
+        // Loading the dump into a new database gives the same dump back:
+        let copy = Connection::open_in_memory().await.unwrap();
+        let sql2 = sql.clone();
+        copy.call(move |conn| conn.execute_batch(&sql2)).await.unwrap();
+        let copied = String::from_utf8(dump(&copy, Vec::new()).await.unwrap()).unwrap();
+        assert_eq!(sql, copied);
+        assert_eq!(get_app_id(&copy).await, 1234);
+        assert_eq!(get_user_version(&copy).await, 3);
+    }
+
+    #[tokio::test]
+    async fn dump_can_be_opened_and_migrated() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("test-db1.app");
+
+        const APP_ID: i32 = 1234;
+        let builder = || ConnectionBuilder::new()
+            .app_id(APP_ID)
+            .add_migration(1, users_table);
+
+        let conn = builder().open_in_memory().await.unwrap();
+        let sql = dump(&conn, Vec::new()).await.unwrap();
+
+        // Dumps from other apps are refused, and nothing is left behind:
+        let res = ConnectionBuilder::new()
+            .add_migration(1, users_table)
+            .open_from_dump(&path, std::io::Cursor::new(sql.clone()))
+            .await;
+        assert!(matches!(res, Err(ConnectionBuilderError::WrongApplicationId(APP_ID))));
+        assert!(!path.exists());
+
+        // Valid dumps are imported and then migrated:
+        let conn = builder()
+            .add_migration(2, data_table)
+            .open_from_dump(&path, std::io::Cursor::new(sql.clone()))
+            .await
+            .unwrap();
+        assert_eq!(get_app_id(&conn).await, APP_ID);
+        assert_eq!(get_user_version(&conn).await, 2);
+        let owner: i32 = conn.call(|conn| {
+            conn.query_row("SELECT owner FROM data JOIN users ON users.id = data.owner", [], |row| row.get(0))
+        }).await.unwrap();
+        assert_eq!(owner, 1);
+
+        // Existing databases aren't overwritten:
+        let res = builder()
+            .open_from_dump(&path, std::io::Cursor::new(sql))
+            .await;
+        assert!(matches!(res, Err(ConnectionBuilderError::Io(e)) if e.kind() == std::io::ErrorKind::AlreadyExists));
+    }
+
+    #[tokio::test]
+    async fn bytes_round_trip() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("test-db1.app");
+
+        const APP_ID: i32 = 1234;
+        let builder = || ConnectionBuilder::new()
+            .app_id(APP_ID)
+            .add_migration(1, users_table);
+
+        // Serialize a database in WAL mode, with some changes still in the WAL:
+        let conn = builder().open(&path).await.unwrap();
+        conn.call(|conn| {
+            conn.pragma_update(None, "journal_mode", "WAL")?;
+            conn.execute("INSERT INTO users VALUES (3, 'Wally')", [])
+        }).await.unwrap();
+        let bytes = serialize(&conn).await.unwrap();
+
+        // The bytes are checked like any existing database is:
+        let res = ConnectionBuilder::new()
+            .add_migration(1, users_table)
+            .open_from_bytes(bytes.clone())
+            .await;
+        assert!(matches!(res, Err(ConnectionBuilderError::WrongApplicationId(APP_ID))));
+
+        // And migrated:
+        let copy = builder()
+            .add_migration(2, data_table)
+            .open_from_bytes(bytes)
+            .await
+            .unwrap();
+        assert_eq!(get_user_version(&copy).await, 2);
+        let names: Vec<String> = copy.call(|conn| {
+            conn.prepare("SELECT name FROM users ORDER BY id")?
+                .query_map([], |row| row.get(0))?
+                .collect()
+        }).await.unwrap();
+        assert_eq!(names, vec!["James", "Bob", "Wally"]);
+
+        // The copy is independent of the original:
+        copy.call(|conn| conn.execute("DELETE FROM users WHERE id = 3", [])).await.unwrap();
+        let n: i64 = conn.call(|conn| conn.query_row("SELECT count(*) FROM users", [], |r| r.get(0))).await.unwrap();
+        assert_eq!(n, 3);
+    }
+
+    #[tokio::test]
+    async fn template_copies_are_independent() {
+        use std::sync::Arc;
+        use std::sync::atomic::{ AtomicUsize, Ordering };
+
+        let migrations = Arc::new(AtomicUsize::new(0));
+        let closed = Arc::new(AtomicUsize::new(0));
+
+        let (m, c) = (migrations.clone(), closed.clone());
+        let template = Template::new(move || {
+            let (m, c) = (m.clone(), c.clone());
+            ConnectionBuilder::new()
+                .app_id(1234)
+                .add_migration(1, move |conn| {
+                    m.fetch_add(1, Ordering::SeqCst);
+                    users_table(conn)
+                })
+                .on_close(move |_| { c.fetch_add(1, Ordering::SeqCst); })
+        }).await.unwrap();
+
+        let conn1 = template.open().await.unwrap();
+        let conn2 = template.open().await.unwrap();
+
+        // Migrations only ran for the template:
+        assert_eq!(migrations.load(Ordering::SeqCst), 1);
+        assert_eq!(get_app_id(&conn1).await, 1234);
+
+        // Changes to one copy don't affect the other:
+        conn1.call(|conn| conn.execute("DELETE FROM users", [])).await.unwrap();
+        let n: i64 = conn2.call(|conn| conn.query_row("SELECT count(*) FROM users", [], |r| r.get(0))).await.unwrap();
+        assert_eq!(n, 2);
+
+        // Each copy (and the template's own connection) calls on_close:
+        conn1.close().await.unwrap();
+        conn2.close().await.unwrap();
+        drop((conn1, conn2));
+        for _ in 0..100 {
+            if closed.load(Ordering::SeqCst) == 3 {
+                break
+            }
+            crate::timer::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(closed.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn snapshots_are_taken_pruned_and_restored() {
+        use std::sync::{ Arc, Mutex };
+        use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("test-db1.app");
+        let snapshot_dir = tempdir.path().join("snapshots");
+
+        let taken = Arc::new(Mutex::new(Vec::new()));
+        let t = taken.clone();
+        let opened = ConnectionBuilder::new()
+            .add_migration(1, users_table)
+            .snapshots(
+                Snapshots::new(&snapshot_dir)
+                    .every(Duration::from_millis(20))
+                    .keep(1, 1, 1)
+                    .on_result(move |res| t.lock().unwrap().push(res.unwrap()))
+            )
+            .open_detailed(&path)
+            .await
+            .unwrap();
+        let task = tokio::spawn(opened.snapshots.unwrap());
+
+        while taken.lock().unwrap().len() < 3 {
+            crate::timer::sleep(Duration::from_millis(10)).await;
+        }
+        // The task stops once the connection is closed:
+        opened.connection.close().await.unwrap();
+        task.await.unwrap();
+
+        // Only the newest snapshot is kept:
+        let newest = taken.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(list_snapshots(&snapshot_dir).unwrap(), vec![newest]);
+
+        // Snapshots can be restored by timestamp:
+        let target = tempdir.path().join("test-db2.app");
+        let conn = ConnectionBuilder::new()
+            .add_migration(1, users_table)
+            .restore_snapshot(&snapshot_dir, SystemTime::now(), &target)
+            .await
+            .unwrap();
+        let n: i64 = conn.call(|conn| conn.query_row("SELECT count(*) FROM users", [], |r| r.get(0))).await.unwrap();
+        assert_eq!(n, 2);
+
+        let res = ConnectionBuilder::new()
+            .add_migration(1, users_table)
+            .restore_snapshot(&snapshot_dir, UNIX_EPOCH, &target)
+            .await;
+        assert!(matches!(res, Err(ConnectionBuilderError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound));
+
+        // Failures are reported, and the task keeps going:
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let e = errors.clone();
+        let task = Snapshots::new(&target)
+            .every(Duration::from_millis(20))
+            .on_result(move |res| e.lock().unwrap().push(res.unwrap_err()))
+            .task(conn.clone());
+        let task = tokio::spawn(task);
+        while errors.lock().unwrap().len() < 2 {
+            crate::timer::sleep(Duration::from_millis(10)).await;
+        }
+        conn.close().await.unwrap();
+        task.await.unwrap();
+        assert!(errors.lock().unwrap().iter().all(|e| matches!(e, SnapshotError::Io(_))));
+    }
+
+    #[test]
+    fn snapshot_retention() {
+        use std::time::{ Duration, UNIX_EPOCH };
+
+        const HOUR: u64 = 60 * 60;
+        const DAY: u64 = 24 * HOUR;
+        // Every 20 minutes for three weeks:
+        let snapshots: Vec<_> = (0..(21 * DAY / 1200)).map(|n| Snapshot {
+            path: format!("snapshot-{n}.db").into(),
+            taken: UNIX_EPOCH + Duration::from_secs(4 * DAY + n * 1200),
+        }).collect();
+
+        let expired = crate::snapshots::expired(&snapshots, (24, 7, 3));
+        let kept: Vec<_> = snapshots.iter().filter(|s| !expired.contains(s)).collect();
+
+        // 24 hourly snapshots, plus 6 more daily ones, and 1 more weekly one (since the
+        // daily ones cover the previous week):
+        assert_eq!(kept.len(), 24 + 6 + 1);
+        assert_eq!(kept.last(), snapshots.last().as_ref());
+        let oldest = kept[0].taken.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert_eq!(oldest % (7 * DAY), 7 * DAY - 1200);
+
+        // Nothing but the newest snapshot is kept if asked to keep nothing:
+        assert_eq!(crate::snapshots::expired(&snapshots, (0, 0, 0)).len(), snapshots.len() - 1);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn json_export_and_import() {
+        use serde_json::json;
+
+        let builder = || ConnectionBuilder::new()
+            .add_migration(1, users_table)
+            .add_migration(2, data_table)
+            .add_migration(3, |conn| conn.execute_batch("
+                CREATE TABLE files (name TEXT PRIMARY KEY, content BLOB, size REAL);
+                INSERT INTO files VALUES ('a.bin', X'000102', 1.5);
+                CREATE TABLE anything (id INTEGER PRIMARY KEY, value);
+                INSERT INTO anything VALUES (1, X'FF'), (2, 'FF'), (3, '[1]');
+            "));
+
+        let conn = builder().open_in_memory().await.unwrap();
+        let mut exported = export_json(&conn, &[]).await.unwrap();
+        assert_eq!(exported, json!({
+            "anything": [{ "id": 1, "value": { "$blob": "/w==" } }, { "id": 2, "value": "FF" }, { "id": 3, "value": "[1]" }],
+            "data": [{ "owner": 1, "text": "James data" }],
+            "files": [{ "name": "a.bin", "content": { "$blob": "AAEC" }, "size": 1.5 }],
+            "users": [{ "id": 1, "name": "James" }, { "id": 2, "name": "Bob" }],
+        }));
+
+        // Patch the export; a new user with data, a renamed user and a changed file:
+        exported["users"].as_array_mut().unwrap().push(json!({ "id": 3, "name": "Wally" }));
+        exported["users"][1]["name"] = json!("Robert");
+        exported["files"][0]["content"] = json!({ "$blob": "/w==" });
+        // Tables without a primary key can't be updated, so only send the new row:
+        exported["data"] = json!([{ "owner": 3, "text": "Wally data" }]);
+        import_json(&conn, exported).await.unwrap();
+
+        // Values keep their storage class, even in columns without a type:
+        let types: String = conn.call(|conn| conn.query_row(
+            "SELECT group_concat(typeof(value)) FROM anything", [], |r| r.get(0)
+        )).await.unwrap();
+        assert_eq!(types, "blob,text,text");
+
+        let patched = export_json(&conn, &["users", "files"]).await.unwrap();
+        assert_eq!(patched, json!({
+            "files": [{ "name": "a.bin", "content": { "$blob": "/w==" }, "size": 1.5 }],
+            "users": [{ "id": 1, "name": "James" }, { "id": 2, "name": "Robert" }, { "id": 3, "name": "Wally" }],
+        }));
+
+        // Unknown columns and tables are refused, and nothing is imported:
+        let res = import_json(&conn, json!({
+            "users": [{ "id": 4, "name": "Nope" }],
+            "data": [{ "owner": 4, "nope": "nope" }],
+        })).await;
+        assert!(matches!(res, Err(JsonError::UnknownColumn { table, column }) if table == "data" && column == "nope"));
+        let res = import_json(&conn, json!({ "nope": [] })).await;
+        assert!(matches!(res, Err(JsonError::UnknownTable(table)) if table == "nope"));
+        let users = export_json(&conn, &["users"]).await.unwrap();
+        assert_eq!(users["users"].as_array().unwrap().len(), 3);
+
+        // Foreign keys are still enforced:
+        let res = import_json(&conn, json!({ "data": [{ "owner": 99, "text": "orphan" }] })).await;
+        assert!(matches!(res, Err(JsonError::Db(_))));
+    }
+
+    #[cfg(feature = "csv")]
+    #[tokio::test]
+    async fn csv_import_and_export() {
+        let conn = ConnectionBuilder::new()
+            .add_migration(1, |conn| conn.execute_batch("
+                CREATE TABLE items (id INTEGER PRIMARY KEY, name TEXT, price REAL, data BLOB, extra ANY) STRICT;
+                CREATE TABLE loose (n INTEGER, note);
+            "))
+            .open_in_memory()
+            .await
+            .unwrap();
+
+        let csv = "\
+            Item,NAME,Price,data,extra,Comment\n\
+            1,Tea,2,ab,x,nice\n\
+            2,,2.5,,3.0,\n\
+            3, Cake ,,,7,\n\
+            4,Jam,1e1,,,\n\
+            5,Bread,0.99,,,\n\
+        ";
+        let imported = CsvImport::new("items")
+            .map("Item", "id")
+            .ignore("Comment")
+            .batch_size(2)
+            .import(&conn, csv.as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(imported, 5);
+
+        // Values are coerced to suit the columns, and exported again:
+        let out = export_csv(&conn, "SELECT * FROM items WHERE id < ?1", [4], Vec::new()).await.unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "\
+            id,name,price,data,extra\n\
+            1,Tea,2.0,6162,x\n\
+            2,,2.5,,3\n\
+            3, Cake ,,,7\n\
+        ");
+        let types: String = conn.call(|conn| conn.query_row(
+            "SELECT group_concat(typeof(price) || '/' || typeof(extra)) FROM items WHERE id < 3", [], |r| r.get(0)
+        )).await.unwrap();
+        assert_eq!(types, "real/text,real/integer");
+
+        // Values that can't go into a STRICT column are an error, but earlier batches remain:
+        let csv = "id,price\n10,1\n11,2\n12,lots\n";
+        let res = CsvImport::new("items").batch_size(2).import(&conn, csv.as_bytes()).await;
+        assert!(matches!(res, Err(CsvError::InvalidValue { line: 4, ref column, ref value }) if column == "price" && value == "lots"), "{res:?}");
+        let n: i64 = conn.call(|conn| conn.query_row("SELECT count(*) FROM items WHERE id >= 10", [], |r| r.get(0))).await.unwrap();
+        assert_eq!(n, 2);
+
+        // Including reals with a fractional part in INTEGER columns:
+        let csv = "id,name\n20.0,Whole\n20.5,Half\n";
+        let res = CsvImport::new("items").import(&conn, csv.as_bytes()).await;
+        assert!(matches!(res, Err(CsvError::InvalidValue { line: 3, ref column, ref value }) if column == "id" && value == "20.5"), "{res:?}");
+
+        // ...whereas other tables keep them as text, like SQLite would:
+        let csv = "n,note\nlots,1\n";
+        CsvImport::new("loose").import(&conn, csv.as_bytes()).await.unwrap();
+        let types: String = conn.call(|conn| conn.query_row("SELECT typeof(n) || '/' || typeof(note) FROM loose", [], |r| r.get(0))).await.unwrap();
+        assert_eq!(types, "text/text");
+
+        // Unknown tables and columns are reported:
+        let res = CsvImport::new("nope").import(&conn, "a\n1\n".as_bytes()).await;
+        assert!(matches!(res, Err(CsvError::UnknownTable(t)) if t == "nope"));
+        let res = CsvImport::new("loose").import(&conn, "nope\n1\n".as_bytes()).await;
+        assert!(matches!(res, Err(CsvError::UnknownColumn { column, .. }) if column == "nope"));
+    }
+
+    #[cfg(feature = "session")]
+    #[tokio::test]
+    async fn changes_can_be_undone_and_redone() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("test-db1.app");
+        let builder = || ConnectionBuilder::new().add_migration(1, users_table);
+        let names = |conn: &Connection| {
+            let conn = conn.clone();
+            async move {
+                conn.call(|conn| conn.query_row("SELECT group_concat(name) FROM (SELECT name FROM users ORDER BY id)", [], |r| r.get::<_, String>(0))).await.unwrap()
+            }
+        };
+
+        let conn = builder().open(&path).await.unwrap();
+        let mut history = UndoStack::new().persist(&conn, "history").await.unwrap();
+        assert!(!history.can_undo());
+
+        history.record(&conn, |conn| conn.execute("INSERT INTO users VALUES (3, 'Wally')", [])).await.unwrap();
+        history.record(&conn, |conn| {
+            conn.execute("UPDATE users SET name = 'Robert' WHERE id = 2", [])?;
+            conn.execute("UPDATE users SET name = 'Jim' WHERE id = 1", [])
+        }).await.unwrap();
+        // Failed operations are rolled back and not recorded:
+        let res = history.record(&conn, |conn| {
+            conn.execute("DELETE FROM users WHERE id = 3", [])?;
+            conn.execute("INSERT INTO users VALUES (1, 'Dupe')", [])
+        }).await;
+        assert!(matches!(res, Err(SessionError::Db(_))));
+        assert_eq!(names(&conn).await, "Jim,Robert,Wally");
+
+        // Each operation is undone as a unit:
+        assert!(history.undo(&conn).await.unwrap());
+        assert_eq!(names(&conn).await, "James,Bob,Wally");
+        assert!(history.undo(&conn).await.unwrap());
+        assert_eq!(names(&conn).await, "James,Bob");
+        assert!(!history.undo(&conn).await.unwrap());
+        assert!(history.redo(&conn).await.unwrap());
+        assert_eq!(names(&conn).await, "James,Bob,Wally");
+
+        // The stack survives reopening the database:
+        conn.close().await.unwrap();
+        drop(conn);
+        let conn = builder().open(&path).await.unwrap();
+        let mut history = UndoStack::new().persist(&conn, "history").await.unwrap();
+        assert!(history.can_undo() && history.can_redo());
+        assert!(history.redo(&conn).await.unwrap());
+        assert_eq!(names(&conn).await, "Jim,Robert,Wally");
+
+        // Changes made elsewhere which conflict stop an undo, leaving the database alone:
+        conn.call(|conn| conn.execute("UPDATE users SET name = 'Bobby' WHERE id = 2", [])).await.unwrap();
+        let res = history.undo(&conn).await;
+        assert!(matches!(res, Err(SessionError::Conflict)));
+        assert_eq!(names(&conn).await, "Jim,Bobby,Wally");
+        assert!(history.can_undo());
+
+        // Recording a new operation clears the redo stack:
+        history.undo(&conn).await.ok();
+        history.clear(&conn).await.unwrap();
+        let mut history = history.limit(1);
+        history.record(&conn, |conn| conn.execute("DELETE FROM users WHERE id = 3", [])).await.unwrap();
+        history.undo(&conn).await.unwrap();
+        history.record(&conn, |conn| conn.execute("DELETE FROM users WHERE id = 1", [])).await.unwrap();
+        assert!(!history.can_redo());
+        history.record(&conn, |conn| conn.execute("DELETE FROM users WHERE id = 2", [])).await.unwrap();
+        assert_eq!(names(&conn).await, "Wally");
+
+        // ...and only `limit` operations are kept:
+        assert!(history.undo(&conn).await.unwrap());
+        assert!(!history.undo(&conn).await.unwrap());
+        assert_eq!(names(&conn).await, "Bobby,Wally");
+        let n: i64 = conn.call(|conn| conn.query_row("SELECT count(*) FROM history", [], |r| r.get(0))).await.unwrap();
+        assert_eq!(n, 1);
+
+        // A persisted stack is trimmed to the limit when it's loaded, too:
+        history.clear(&conn).await.unwrap();
+        let mut history = UndoStack::new().persist(&conn, "history").await.unwrap();
+        for (id, name) in [(4, "Four"), (5, "Five"), (6, "Six")] {
+            history.record(&conn, move |conn| conn.execute("INSERT INTO users VALUES (?1, ?2)", (id, name))).await.unwrap();
+        }
+        let mut history = UndoStack::new().limit(2).persist(&conn, "history").await.unwrap();
+        let n: i64 = conn.call(|conn| conn.query_row("SELECT count(*) FROM history", [], |r| r.get(0))).await.unwrap();
+        assert_eq!(n, 2);
+        assert!(history.undo(&conn).await.unwrap());
+        assert!(history.undo(&conn).await.unwrap());
+        assert!(!history.undo(&conn).await.unwrap());
+        assert_eq!(names(&conn).await, "Bobby,Wally,Four");
+    }
+
+    #[cfg(feature = "session")]
+    #[tokio::test]
+    async fn replicas_sync_changes() {
+        use std::sync::{ Arc, Mutex };
+        use rusqlite::types::Value;
+
+        let builder = || ConnectionBuilder::new()
+            .add_migration(1, |conn| conn.execute_batch("CREATE TABLE note (id TEXT PRIMARY KEY, text TEXT NOT NULL)"));
+        let notes = |conn: &Connection| {
+            let conn = conn.clone();
+            async move {
+                conn.call(|conn| conn.query_row("SELECT group_concat(id || '=' || text) FROM (SELECT * FROM note ORDER BY id)", [], |r| r.get::<_, String>(0))).await.unwrap()
+            }
+        };
+        let no_conflicts = |conflict| panic!("unexpected conflict: {conflict:?}");
+
+        let laptop_conn = builder().open_in_memory().await.unwrap();
+        let desktop_conn = builder().open_in_memory().await.unwrap();
+        let laptop = Replica::open(&laptop_conn, "laptop").await.unwrap();
+        let desktop = Replica::open(&desktop_conn, "desktop").await.unwrap();
+
+        laptop.record(&laptop_conn, |conn| conn.execute_batch("
+            INSERT INTO note VALUES ('a', 'hello');
+            INSERT INTO note VALUES ('b', 'world');
+        ")).await.unwrap();
+
+        // Changes survive being turned into bytes, and importing them twice is fine:
+        let changes = laptop.export(&laptop_conn, "desktop").await.unwrap();
+        let changes = Changes::from_bytes(&changes.to_bytes()).unwrap();
+        assert_eq!(changes.origin(), "laptop");
+        desktop.import(&desktop_conn, changes.clone(), no_conflicts).await.unwrap();
+        desktop.import(&desktop_conn, changes.clone(), no_conflicts).await.unwrap();
+        assert_eq!(notes(&desktop_conn).await, "a=hello,b=world");
+
+        // Changes aren't sent back to where they came from, and are acknowledged:
+        let changes = desktop.export(&desktop_conn, "laptop").await.unwrap();
+        assert!(changes.is_empty());
+        laptop.import(&laptop_conn, changes, no_conflicts).await.unwrap();
+        assert!(laptop.export(&laptop_conn, "desktop").await.unwrap().is_empty());
+
+        // Both sides change the same row:
+        laptop.record(&laptop_conn, |conn| conn.execute("UPDATE note SET text = 'laptop' WHERE id = 'a'", [])).await.unwrap();
+        desktop.record(&desktop_conn, |conn| conn.execute("UPDATE note SET text = 'desktop' WHERE id = 'a'", [])).await.unwrap();
+
+        // Aborting leaves everything as it was:
+        let conflicts = Arc::new(Mutex::new(Vec::new()));
+        let c = conflicts.clone();
+        let changes = laptop.export(&laptop_conn, "desktop").await.unwrap();
+        let res = desktop.import(&desktop_conn, changes.clone(), move |conflict| {
+            c.lock().unwrap().push(conflict);
+            Resolution::Abort
+        }).await;
+        assert!(matches!(res, Err(SessionError::Conflict)));
+        assert_eq!(notes(&desktop_conn).await, "a=desktop,b=world");
+        let conflict = conflicts.lock().unwrap().pop().unwrap();
+        assert_eq!(conflict.table, "note");
+        assert_eq!(conflict.kind, ConflictKind::Data);
+        assert_eq!(conflict.local, vec![Value::Text("a".into()), Value::Text("desktop".into())]);
+        assert_eq!(conflict.remote, vec![None, Some(Value::Text("laptop".into()))]);
+
+        // The changes can be imported again, taking the remote side...
+        desktop.import(&desktop_conn, changes, |_| Resolution::TakeRemote).await.unwrap();
+        assert_eq!(notes(&desktop_conn).await, "a=laptop,b=world");
+
+        // ...or keeping the local side:
+        let changes = desktop.export(&desktop_conn, "laptop").await.unwrap();
+        let c = conflicts.clone();
+        laptop.import(&laptop_conn, changes, move |conflict| {
+            c.lock().unwrap().push(conflict);
+            Resolution::KeepLocal
+        }).await.unwrap();
+        assert_eq!(notes(&laptop_conn).await, "a=laptop,b=world");
+        assert_eq!(conflicts.lock().unwrap().len(), 1);
+
+        // Everything made after some point can be exported:
+        let position = laptop.position(&laptop_conn).await.unwrap();
+        laptop.record(&laptop_conn, |conn| conn.execute("DELETE FROM note WHERE id = 'b'", [])).await.unwrap();
+        let changes = laptop.export_since(&laptop_conn, position).await.unwrap();
+        desktop.import(&desktop_conn, changes, no_conflicts).await.unwrap();
+        assert_eq!(notes(&desktop_conn).await, "a=laptop");
+
+        // Garbage and our own changes are refused:
+        assert!(matches!(Changes::from_bytes(b"nope"), Err(SessionError::InvalidChanges(_))));
+        let changes = laptop.export(&laptop_conn, "desktop").await.unwrap();
+        let res = laptop.import(&laptop_conn, changes, no_conflicts).await;
+        assert!(matches!(res, Err(SessionError::InvalidChanges(_))));
+    }
+
+    #[cfg(feature = "session")]
+    #[tokio::test]
+    async fn replicas_sync_in_a_ring() {
+        let builder = || ConnectionBuilder::new()
+            .add_migration(1, |conn| conn.execute_batch("CREATE TABLE note (id TEXT PRIMARY KEY, text TEXT NOT NULL)"));
+        let count = |conn: &Connection| {
+            let conn = conn.clone();
+            async move {
+                conn.call(|conn| conn.query_row("SELECT count(*) FROM note", [], |r| r.get::<_, i64>(0))).await.unwrap()
+            }
+        };
+        let no_conflicts = |conflict| panic!("unexpected conflict: {conflict:?}");
+
+        let mut conns = Vec::new();
+        let mut replicas = Vec::new();
+        for name in ["a", "b", "c"] {
+            let conn = builder().open_in_memory().await.unwrap();
+            replicas.push(Replica::open(&conn, name).await.unwrap());
+            conns.push(conn);
+        }
+        // Pass changes from one replica to the next, A -> B -> C -> A.
+        let sync = |from: usize| {
+            let (conns, replicas) = (conns.clone(), replicas.clone());
+            async move {
+                let to = (from + 1) % 3;
+                let changes = replicas[from].export(&conns[from], replicas[to].name()).await.unwrap();
+                let changes = Changes::from_bytes(&changes.to_bytes()).unwrap();
+                let is_empty = changes.is_empty();
+                replicas[to].import(&conns[to], changes, no_conflicts).await.unwrap();
+                is_empty
+            }
+        };
+
+        replicas[0].record(&conns[0], |conn| conn.execute("INSERT INTO note VALUES ('a', 'from a')", [])).await.unwrap();
+        assert!(!sync(0).await);
+        assert!(!sync(1).await);
+        assert_eq!(count(&conns[2]).await, 1);
+
+        // A's changes aren't sent back to it by C:
+        assert!(sync(2).await);
+
+        // Changes which arrive by two routes are only applied once:
+        replicas[1].record(&conns[1], |conn| conn.execute("INSERT INTO note VALUES ('b', 'from b')", [])).await.unwrap();
+        let changes = replicas[1].export(&conns[1], "a").await.unwrap();
+        replicas[0].import(&conns[0], changes, no_conflicts).await.unwrap();
+        assert!(!sync(1).await);
+        assert!(!sync(2).await);
+        assert_eq!(count(&conns[0]).await, 2);
+
+        // And A doesn't pass them on to B, which made them:
+        assert!(sync(0).await);
+    }
+
+    #[cfg(feature = "load_extension")]
+    #[tokio::test]
+    async fn missing_extension_is_reported() {
+        let conn = unsafe {
+            ConnectionBuilder::new()
+                .load_extension("./does-not-exist", None)
+        }
+            .add_migration(1, users_table)
+            .open_in_memory()
+            .await;
+
+        assert!(
+            matches!(conn, Err(ConnectionBuilderError::LoadExtension { .. }))
+        );
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[tokio::test]
+    async fn encrypted_db_needs_right_key() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("test-db1.app");
+
+        let conn = ConnectionBuilder::new()
+            .key("secret")
+            .add_migration(1, users_table)
+            .open(&path)
+            .await
+            .unwrap();
+
+        rekey(&conn, "new secret").await.unwrap();
+        drop(conn);
+
+        // The old key no longer works:
+        let conn = ConnectionBuilder::new()
+            .key("secret")
+            .add_migration(1, users_table)
+            .open(&path)
+            .await;
+
+        assert!(
+            matches!(conn, Err(ConnectionBuilderError::WrongKey))
+        );
+
+        // But the new one does:
+        let conn = ConnectionBuilder::new()
+            .key("new secret")
+            .add_migration(1, users_table)
+            .open(&path)
+            .await
+            .unwrap();
+
+        assert_eq!(get_user_version(&conn).await, 1);
+    }
 }
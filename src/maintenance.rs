@@ -0,0 +1,294 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ Context, Poll };
+use std::time::{ Duration, Instant };
+use async_rusqlite::Connection;
+
+use crate::signal::Wait;
+use crate::timer;
+
+/// Configure regular maintenance jobs to run against a database. Use
+/// [`crate::ConnectionBuilder::maintenance`] or [`Maintenance::task`] to
+/// get hold of a [`MaintenanceTask`] which runs them.
+///
+/// ```rust
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::time::Duration;
+/// use sqliter::{ ConnectionBuilder, Maintenance };
+/// # let dir = tempfile::tempdir()?;
+/// # let path = dir.path().join("my-app.db");
+///
+/// let opened = ConnectionBuilder::<rusqlite::Error>::new()
+///     .maintenance(
+///         Maintenance::new()
+///             .optimize_every(Duration::from_secs(60 * 60))
+///             .checkpoint_every(Duration::from_secs(5 * 60))
+///             .on_result(|job, res| {
+///                 if let Err(e) = res {
+///                     eprintln!("{job:?} failed: {e}");
+///                 }
+///             })
+///     )
+///     .open_detailed(path)
+///     .await?;
+///
+/// // The task is runtime agnostic; spawn it onto whichever runtime you use:
+/// tokio::spawn(opened.maintenance.unwrap());
+/// # Ok(())
+/// # }
+/// ```
+pub struct Maintenance {
+    optimize: Option<Duration>,
+    checkpoint: Option<Duration>,
+    incremental_vacuum: Option<(Duration, Option<u32>)>,
+    on_result: Option<Box<OnResultFn>>,
+}
+
+type OnResultFn = dyn FnMut(MaintenanceJob, Result<(), rusqlite::Error>) + Send + 'static;
+
+/// A maintenance job. See [`Maintenance`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaintenanceJob {
+    /// `PRAGMA optimize`.
+    Optimize,
+    /// `PRAGMA wal_checkpoint(TRUNCATE)`.
+    Checkpoint,
+    /// `PRAGMA incremental_vacuum`.
+    IncrementalVacuum,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Maintenance {
+    /// Configure a new set of maintenance jobs. No jobs will run by default.
+    pub fn new() -> Self {
+        Maintenance {
+            optimize: None,
+            checkpoint: None,
+            incremental_vacuum: None,
+            on_result: None,
+        }
+    }
+
+    /// Run `PRAGMA optimize` at the given interval.
+    pub fn optimize_every(mut self, interval: Duration) -> Self {
+        self.optimize = Some(interval);
+        self
+    }
+
+    /// Run `PRAGMA wal_checkpoint(TRUNCATE)` at the given interval. This only
+    /// does anything if the database is in WAL mode.
+    pub fn checkpoint_every(mut self, interval: Duration) -> Self {
+        self.checkpoint = Some(interval);
+        self
+    }
+
+    /// Run `PRAGMA incremental_vacuum` at the given interval, freeing up to `pages`
+    /// pages (or every free page if `None`). This only does anything if the database
+    /// has `auto_vacuum = INCREMENTAL`.
+    pub fn incremental_vacuum_every(mut self, interval: Duration, pages: Option<u32>) -> Self {
+        self.incremental_vacuum = Some((interval, pages));
+        self
+    }
+
+    /// Called with the result of each job that is run.
+    pub fn on_result<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(MaintenanceJob, Result<(), rusqlite::Error>) + Send + 'static
+    {
+        self.on_result = Some(Box::new(f));
+        self
+    }
+
+    /// Return a task which runs the configured jobs against the given connection.
+    /// This must be spawned or otherwise polled to completion for anything to happen.
+    ///
+    /// The task holds onto the connection, and so keeps it open until either the task
+    /// is dropped, or the connection is explicitly closed via [`Connection::close`].
+    pub fn task(self, conn: Connection) -> MaintenanceTask {
+        MaintenanceTask::new(self.run(conn))
+    }
+
+    async fn run(mut self, conn: Connection) {
+        let now = Instant::now();
+        let mut jobs = Vec::new();
+        if let Some(interval) = self.optimize {
+            jobs.push((MaintenanceJob::Optimize, interval, now + interval));
+        }
+        if let Some(interval) = self.checkpoint {
+            jobs.push((MaintenanceJob::Checkpoint, interval, now + interval));
+        }
+        if let Some((interval, _)) = self.incremental_vacuum {
+            jobs.push((MaintenanceJob::IncrementalVacuum, interval, now + interval));
+        }
+
+        loop {
+            // Wait until the next job is due.
+            let Some(next_at) = jobs.iter().map(|(_, _, at)| *at).min() else {
+                return
+            };
+            timer::sleep(next_at.saturating_duration_since(Instant::now())).await;
+
+            for (job, interval, at) in &mut jobs {
+                if *at > Instant::now() {
+                    continue
+                }
+                *at = Instant::now() + *interval;
+
+                let job = *job;
+                let vacuum_pages = self.incremental_vacuum.and_then(|(_, pages)| pages);
+                let res = conn.call(move |conn| run_job(conn, job, vacuum_pages)).await;
+
+                // Stop once the connection has been closed.
+                if let Err(JobError::Closed) = res {
+                    return
+                }
+                if let Some(on_result) = &mut self.on_result {
+                    on_result(job, res.map_err(JobError::into_rusqlite));
+                }
+            }
+        }
+    }
+}
+
+fn run_job(conn: &mut rusqlite::Connection, job: MaintenanceJob, vacuum_pages: Option<u32>) -> Result<(), JobError> {
+    match job {
+        MaintenanceJob::Optimize => {
+            conn.execute_batch("PRAGMA optimize")?;
+        },
+        MaintenanceJob::Checkpoint => {
+            conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        },
+        MaintenanceJob::IncrementalVacuum => {
+            match vacuum_pages {
+                Some(pages) => conn.execute_batch(&format!("PRAGMA incremental_vacuum({pages})"))?,
+                None => conn.execute_batch("PRAGMA incremental_vacuum")?,
+            }
+        },
+    }
+    Ok(())
+}
+
+// Distinguish the connection closing from other errors.
+enum JobError {
+    Closed,
+    Db(rusqlite::Error),
+}
+
+impl JobError {
+    fn into_rusqlite(self) -> rusqlite::Error {
+        match self {
+            JobError::Closed => async_rusqlite::AlreadyClosed.into(),
+            JobError::Db(e) => e,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for JobError {
+    fn from(e: rusqlite::Error) -> Self {
+        JobError::Db(e)
+    }
+}
+
+impl From<async_rusqlite::AlreadyClosed> for JobError {
+    fn from(_: async_rusqlite::AlreadyClosed) -> Self {
+        JobError::Closed
+    }
+}
+
+/// Housekeeping to perform when a connection is closed. See
+/// [`crate::ConnectionBuilder::housekeeping`]. Nothing is done by default.
+///
+/// Since there's nobody to report them to, any errors are ignored.
+#[derive(Debug, Clone, Default)]
+pub struct Housekeeping {
+    optimize: bool,
+    checkpoint: bool,
+    vacuum_above: Option<u64>,
+}
+
+impl Housekeeping {
+    /// Configure new housekeeping.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `PRAGMA optimize`.
+    pub fn optimize(mut self) -> Self {
+        self.optimize = true;
+        self
+    }
+
+    /// Run `PRAGMA wal_checkpoint(TRUNCATE)` to checkpoint any WAL file.
+    pub fn checkpoint(mut self) -> Self {
+        self.checkpoint = true;
+        self
+    }
+
+    /// Run `VACUUM` if the number of free pages in the database is above the given threshold.
+    pub fn vacuum_if_freelist_above(mut self, pages: u64) -> Self {
+        self.vacuum_above = Some(pages);
+        self
+    }
+
+    pub(crate) fn run(&self, conn: &rusqlite::Connection) {
+        if self.optimize {
+            let _ = conn.execute_batch("PRAGMA optimize");
+        }
+        if let Some(threshold) = self.vacuum_above {
+            let freelist = conn.pragma_query_value(None, "freelist_count", |row| row.get::<_, u64>(0));
+            if matches!(freelist, Ok(n) if n > threshold) {
+                let _ = conn.execute_batch("VACUUM");
+            }
+        }
+        // Checkpoint last, to include any changes that vacuuming made.
+        if self.checkpoint {
+            let _ = conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()));
+        }
+    }
+}
+
+/// A runtime agnostic task which runs maintenance jobs or takes snapshots. See
+/// [`Maintenance`] and [`crate::Snapshots`].
+pub struct MaintenanceTask(Pin<Box<dyn Future<Output = ()> + Send + 'static>>);
+
+impl MaintenanceTask {
+    pub(crate) fn new<F: Future<Output = ()> + Send + 'static>(fut: F) -> Self {
+        MaintenanceTask(Box::pin(fut))
+    }
+
+    // Finish this task as soon as `stop` completes, dropping the future it was
+    // running (and with it, any connection that the future holds onto).
+    pub(crate) fn stop_on(self, mut stop: Wait) -> Self {
+        let mut fut = Some(self.0);
+        MaintenanceTask::new(std::future::poll_fn(move |cx| {
+            if Pin::new(&mut stop).poll(cx).is_ready() {
+                fut = None;
+                return Poll::Ready(())
+            }
+            match &mut fut {
+                Some(f) => f.as_mut().poll(cx),
+                None => Poll::Ready(()),
+            }
+        }))
+    }
+}
+
+impl Future for MaintenanceTask {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.0.as_mut().poll(cx)
+    }
+}
+
+impl std::fmt::Debug for MaintenanceTask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MaintenanceTask").finish_non_exhaustive()
+    }
+}
@@ -63,6 +63,11 @@ impl <E> Migrations<E> {
         self
     }
 
+    /// The highest version of any migration, or 0 if there are none.
+    pub fn latest_version(&self) -> i32 {
+        self.migrations.iter().map(|Reverse(m)| m.version).max().unwrap_or(0)
+    }
+
     /// Iterate over the migrations, lowest to highest version.
     pub(crate) fn iter(&self) -> impl Iterator<Item = (i32, bool, &MigrationFn<E>)> {
         self.migrations.iter().map(|Reverse(m)| (m.version, m.perform_in_transaction, &*m.migration))
@@ -92,6 +97,6 @@ impl <E> Ord for Migration<E> {
 
 impl <E> PartialOrd for Migration<E> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.version.partial_cmp(&other.version)
+        Some(self.cmp(other))
     }
 }
\ No newline at end of file
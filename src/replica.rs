@@ -0,0 +1,443 @@
+use std::sync::{ Arc, Mutex };
+use async_rusqlite::Connection;
+use rusqlite::{ ErrorCode, OptionalExtension };
+use rusqlite::session::{ ChangesetItem, ConflictAction, ConflictType };
+use rusqlite::types::Value;
+use crate::error::SessionError;
+use crate::session::record_changes;
+
+const LOG_TABLE: &str = "sqliter_sync_log";
+const PEERS_TABLE: &str = "sqliter_sync_peers";
+const MAGIC: &[u8] = b"sqliter-changes\x02";
+
+/// One of several copies of a database which are kept in sync by exchanging
+/// [`Changes`], using the SQLite session extension.
+///
+/// Each replica has a name which is unique among the replicas that it syncs with.
+/// Changes made through [`Replica::record`] are kept in a log, along with changes
+/// imported from other replicas, and can be exported for any other replica. Only
+/// changes that the other replica hasn't acknowledged yet are exported (it
+/// acknowledges them by exporting its own changes back), and changes are never
+/// sent back to the replica that they were imported from or that made them.
+/// Importing the same changes twice, or receiving them by more than one route,
+/// does nothing after the first time.
+///
+/// Two tables are created to keep track of this, which should be included in any
+/// schema given to [`crate::ConnectionBuilder::verify_schema`] or
+/// [`crate::ConnectionBuilder::schema`]:
+///
+/// ```sql
+/// CREATE TABLE sqliter_sync_log (seq INTEGER PRIMARY KEY, origin TEXT, author TEXT, author_seq INTEGER, changeset BLOB NOT NULL);
+/// CREATE TABLE sqliter_sync_peers (peer TEXT PRIMARY KEY, sent INTEGER NOT NULL, received INTEGER NOT NULL);
+/// ```
+///
+/// Only tables in the `main` database which have a primary key are synced; see
+/// <https://sqlite.org/sessionintro.html#limitations>.
+///
+/// ```rust
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use sqliter::{ ConnectionBuilder, Replica, Resolution };
+///
+/// let builder = || ConnectionBuilder::new()
+///     .add_migration(1, |conn| conn.execute_batch("
+///         CREATE TABLE note (id TEXT PRIMARY KEY, text TEXT NOT NULL);
+///     "));
+/// let laptop_conn = builder().open_in_memory().await?;
+/// let desktop_conn = builder().open_in_memory().await?;
+/// let laptop = Replica::open(&laptop_conn, "laptop").await?;
+/// let desktop = Replica::open(&desktop_conn, "desktop").await?;
+///
+/// laptop.record(&laptop_conn, |conn| {
+///     conn.execute("INSERT INTO note VALUES ('a', 'hello')", [])
+/// }).await?;
+///
+/// // Changes can be written to a file and read back on the other machine:
+/// let bytes = laptop.export(&laptop_conn, "desktop").await?.to_bytes();
+/// let changes = sqliter::Changes::from_bytes(&bytes)?;
+/// desktop.import(&desktop_conn, changes, |_conflict| Resolution::TakeRemote).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Replica {
+    name: String,
+}
+
+/// Changes exported from one [`Replica`] to be imported into another.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Changes {
+    origin: String,
+    // The last of the recipient's own changes that the origin has imported.
+    ack: i64,
+    // Changesets from the origin's log.
+    entries: Vec<Entry>,
+}
+
+// A changeset in a replica's log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    // Its position in the log of the replica that exported it.
+    seq: i64,
+    // The replica that made the changes, and the position in its own log.
+    author: String,
+    author_seq: i64,
+    changeset: Vec<u8>,
+}
+
+/// A change from another replica which conflicts with the local database. See
+/// [`Replica::import`].
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct Conflict {
+    /// The table that the change is for.
+    pub table: String,
+    /// What sort of conflict this is.
+    pub kind: ConflictKind,
+    /// The conflicting row in the local database, for [`ConflictKind::Data`] and
+    /// [`ConflictKind::Conflict`] conflicts. Empty otherwise.
+    pub local: Vec<Value>,
+    /// The values that the change would set each column to, or `None` for columns
+    /// that it leaves alone. Empty if the change deletes a row.
+    pub remote: Vec<Option<Value>>,
+}
+
+/// The kinds of [`Conflict`]; see <https://sqlite.org/session/c_changeset_conflict.html>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConflictKind {
+    /// The row to update or delete has been changed locally.
+    Data,
+    /// The row to update or delete no longer exists locally.
+    NotFound,
+    /// The row to insert already exists locally.
+    Conflict,
+    /// The change would violate a constraint.
+    Constraint,
+    /// The changes would leave foreign keys violated once applied.
+    ForeignKey,
+}
+
+/// How to resolve a [`Conflict`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// Skip the remote change and keep the local row as it is. For
+    /// [`ConflictKind::ForeignKey`], this keeps the changes anyway.
+    KeepLocal,
+    /// Apply the remote change over the local row. This is only possible for
+    /// [`ConflictKind::Data`] and [`ConflictKind::Conflict`], and is otherwise
+    /// treated like [`Resolution::KeepLocal`].
+    TakeRemote,
+    /// Abandon the import, leaving the local database untouched. The import
+    /// returns [`SessionError::Conflict`].
+    Abort,
+}
+
+impl Replica {
+    /// Use the database as the replica with the given name, creating the tables
+    /// needed to keep track of changes if they don't exist.
+    pub async fn open<S: Into<String>>(conn: &Connection, name: S) -> Result<Self, SessionError> {
+        conn.call(|conn| conn.execute_batch(&format!("
+            CREATE TABLE IF NOT EXISTS {LOG_TABLE} (seq INTEGER PRIMARY KEY, origin TEXT, author TEXT, author_seq INTEGER, changeset BLOB NOT NULL);
+            CREATE TABLE IF NOT EXISTS {PEERS_TABLE} (peer TEXT PRIMARY KEY, sent INTEGER NOT NULL, received INTEGER NOT NULL);
+        "))).await?;
+        Ok(Replica { name: name.into() })
+    }
+
+    /// The name of this replica.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Run `f` in a transaction, recording the changes that it makes so that they
+    /// can be exported to other replicas. If `f` returns an error, the transaction
+    /// is rolled back and nothing is recorded.
+    pub async fn record<F, R>(&self, conn: &Connection, f: F) -> Result<R, SessionError>
+    where
+        F: FnOnce(&rusqlite::Connection) -> Result<R, rusqlite::Error> + Send + 'static,
+        R: Send + 'static
+    {
+        conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            let (res, changeset) = record_changes(&tx, sync_tables(), f)?;
+            append_to_log(&tx, None, &changeset)?;
+            tx.commit()?;
+            Ok(res)
+        }).await
+    }
+
+    /// The sequence number of the latest change in the log, for use with
+    /// [`Replica::export_since`].
+    pub async fn position(&self, conn: &Connection) -> Result<i64, SessionError> {
+        conn.call(|conn| {
+            let seq = conn.query_row(&format!("SELECT coalesce(max(seq), 0) FROM {LOG_TABLE}"), [], |row| row.get(0))?;
+            Ok(seq)
+        }).await
+    }
+
+    /// Export the changes that the given replica hasn't acknowledged yet, leaving
+    /// out any that were imported from it or that it made. The replica is added to the peers table
+    /// if it isn't already there.
+    pub async fn export(&self, conn: &Connection, peer: &str) -> Result<Changes, SessionError> {
+        let origin = self.name.clone();
+        let peer = peer.to_owned();
+        conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            let (sent, received) = peer_state(&tx, &peer)?;
+            let entries = log_entries(&tx, &origin, sent, Some(&peer))?;
+            tx.commit()?;
+            Ok(Changes { origin, ack: received, entries })
+        }).await
+    }
+
+    /// Export every change made after the given position (see [`Replica::position`]),
+    /// regardless of where it came from. Importing these acknowledges nothing.
+    pub async fn export_since(&self, conn: &Connection, seq: i64) -> Result<Changes, SessionError> {
+        let origin = self.name.clone();
+        conn.call(move |conn| {
+            let entries = log_entries(conn, &origin, seq, None)?;
+            Ok(Changes { origin, ack: 0, entries })
+        }).await
+    }
+
+    /// Apply changes exported from another replica, in a single transaction. Any
+    /// conflicts with the local database are handed to `resolve` to decide what
+    /// to do about them. Changes which have already been imported (perhaps from
+    /// another replica), or which were made here, are skipped.
+    ///
+    /// The applied changes are added to the log, so that they can be passed on to
+    /// other replicas.
+    pub async fn import<F>(&self, conn: &Connection, changes: Changes, resolve: F) -> Result<(), SessionError>
+    where
+        F: FnMut(Conflict) -> Resolution + Send + 'static
+    {
+        if changes.origin == self.name {
+            return Err(SessionError::InvalidChanges(format!("changes came from this replica ({})", self.name)))
+        }
+
+        let resolve = Arc::new(Mutex::new(resolve));
+        let name = self.name.clone();
+        conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            let (sent, received) = peer_state(&tx, &changes.origin)?;
+
+            let mut last = received;
+            for entry in changes.entries {
+                if entry.seq <= received {
+                    continue
+                }
+                last = entry.seq;
+                // Changes can find their way back to us, or arrive by more than one route.
+                if entry.author == name || is_logged(&tx, &entry.author, entry.author_seq)? {
+                    continue
+                }
+                let resolve = resolve.clone();
+                let ((), applied) = record_changes(&tx, sync_tables(), |conn| {
+                    conn.apply_strm(
+                        &mut &*entry.changeset,
+                        None::<fn(&str) -> bool>,
+                        move |kind, item| on_conflict(kind, &item, &mut *resolve.lock().unwrap())
+                    )
+                }).map_err(|e| match e.sqlite_error_code() {
+                    Some(ErrorCode::OperationAborted) => SessionError::Conflict,
+                    _ => e.into()
+                })?;
+                append_to_log(&tx, Some((&changes.origin, &entry.author, entry.author_seq)), &applied)?;
+            }
+
+            tx.execute(
+                &format!("UPDATE {PEERS_TABLE} SET sent = max(sent, ?2), received = ?3 WHERE peer = ?1"),
+                (&changes.origin, sent.max(changes.ack), last)
+            )?;
+            tx.commit()?;
+            Ok(())
+        }).await
+    }
+}
+
+impl Changes {
+    /// The name of the replica that these changes came from.
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    /// Are there no changes here? Empty changes are still worth importing, since
+    /// they acknowledge the changes that the origin has received.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Encode the changes as bytes, to be written to a file or sent elsewhere.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        put_bytes(&mut out, self.origin.as_bytes());
+        out.extend_from_slice(&self.ack.to_le_bytes());
+        out.extend_from_slice(&(self.entries.len() as u64).to_le_bytes());
+        for entry in &self.entries {
+            out.extend_from_slice(&entry.seq.to_le_bytes());
+            put_bytes(&mut out, entry.author.as_bytes());
+            out.extend_from_slice(&entry.author_seq.to_le_bytes());
+            put_bytes(&mut out, &entry.changeset);
+        }
+        out
+    }
+
+    /// Decode changes from bytes produced by [`Changes::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SessionError> {
+        let invalid = || SessionError::InvalidChanges("unexpected end of input".to_owned());
+        let mut input = bytes.strip_prefix(MAGIC)
+            .ok_or_else(|| SessionError::InvalidChanges("not an export of changes".to_owned()))?;
+
+        let origin = take_string(&mut input, "origin")?;
+        let ack = take_u64(&mut input).ok_or_else(invalid)? as i64;
+        let count = take_u64(&mut input).ok_or_else(invalid)?;
+
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let seq = take_u64(&mut input).ok_or_else(invalid)? as i64;
+            let author = take_string(&mut input, "author")?;
+            let author_seq = take_u64(&mut input).ok_or_else(invalid)? as i64;
+            let changeset = take_bytes(&mut input).ok_or_else(invalid)?.to_vec();
+            entries.push(Entry { seq, author, author_seq, changeset });
+        }
+        if !input.is_empty() {
+            return Err(SessionError::InvalidChanges("unexpected bytes at the end".to_owned()))
+        }
+        Ok(Changes { origin, ack, entries })
+    }
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn take_u64(input: &mut &[u8]) -> Option<u64> {
+    let (n, rest) = input.split_first_chunk::<8>()?;
+    *input = rest;
+    Some(u64::from_le_bytes(*n))
+}
+
+fn take_bytes<'a>(input: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = usize::try_from(take_u64(input)?).ok()?;
+    if input.len() < len {
+        return None
+    }
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+    Some(bytes)
+}
+
+fn take_string(input: &mut &[u8], what: &str) -> Result<String, SessionError> {
+    let bytes = take_bytes(input)
+        .ok_or_else(|| SessionError::InvalidChanges("unexpected end of input".to_owned()))?;
+    String::from_utf8(bytes.to_vec())
+        .map_err(|_| SessionError::InvalidChanges(format!("{what} is not valid UTF-8")))
+}
+
+// The tables which we use to keep track of syncing, and so never sync.
+fn sync_tables() -> Vec<String> {
+    vec![LOG_TABLE.to_owned(), PEERS_TABLE.to_owned()]
+}
+
+// Add a changeset to the log. Imported changes note the peer they came from, and the
+// replica that made them along with where they are in its log.
+fn append_to_log(conn: &rusqlite::Connection, imported: Option<(&str, &str, i64)>, changeset: &[u8]) -> Result<(), rusqlite::Error> {
+    if !changeset.is_empty() {
+        let (origin, author, author_seq) = match imported {
+            Some((origin, author, author_seq)) => (Some(origin), Some(author), Some(author_seq)),
+            None => (None, None, None),
+        };
+        conn.execute(
+            &format!("INSERT INTO {LOG_TABLE} (origin, author, author_seq, changeset) VALUES (?1, ?2, ?3, ?4)"),
+            (origin, author, author_seq, changeset)
+        )?;
+    }
+    Ok(())
+}
+
+// Have we already imported the given changes made by another replica?
+fn is_logged(conn: &rusqlite::Connection, author: &str, author_seq: i64) -> Result<bool, rusqlite::Error> {
+    conn.prepare_cached(&format!("SELECT 1 FROM {LOG_TABLE} WHERE author = ?1 AND author_seq = ?2"))?
+        .exists((author, author_seq))
+}
+
+// The changes in the log after `seq`, leaving out those which came from or were made by
+// `peer`. Our own changes are attributed to `name`.
+fn log_entries(conn: &rusqlite::Connection, name: &str, seq: i64, peer: Option<&str>) -> Result<Vec<Entry>, rusqlite::Error> {
+    let mut stmt = conn.prepare(&format!("
+        SELECT seq, coalesce(author, ?3), coalesce(author_seq, seq), changeset FROM {LOG_TABLE}
+        WHERE seq > ?1
+            AND (origin IS NULL OR origin IS NOT ?2)
+            AND (author IS NULL OR author IS NOT ?2)
+        ORDER BY seq
+    "))?;
+    let entries = stmt.query_map((seq, peer, name), |row| Ok(Entry {
+        seq: row.get(0)?,
+        author: row.get(1)?,
+        author_seq: row.get(2)?,
+        changeset: row.get(3)?,
+    }))?.collect();
+    entries
+}
+
+// How far we've sent and received changes to and from a peer, adding it if it's new.
+fn peer_state(conn: &rusqlite::Connection, peer: &str) -> Result<(i64, i64), rusqlite::Error> {
+    let state = conn.query_row(
+        &format!("SELECT sent, received FROM {PEERS_TABLE} WHERE peer = ?1"),
+        [peer],
+        |row| Ok((row.get(0)?, row.get(1)?))
+    ).optional()?;
+
+    match state {
+        Some(state) => Ok(state),
+        None => {
+            conn.execute(&format!("INSERT INTO {PEERS_TABLE} (peer, sent, received) VALUES (?1, 0, 0)"), [peer])?;
+            Ok((0, 0))
+        }
+    }
+}
+
+// Describe a conflict, ask how to resolve it, and tell SQLite what to do.
+fn on_conflict(kind: ConflictType, item: &ChangesetItem, resolve: &mut dyn FnMut(Conflict) -> Resolution) -> ConflictAction {
+    let kind = match kind {
+        ConflictType::SQLITE_CHANGESET_DATA => ConflictKind::Data,
+        ConflictType::SQLITE_CHANGESET_NOTFOUND => ConflictKind::NotFound,
+        ConflictType::SQLITE_CHANGESET_CONFLICT => ConflictKind::Conflict,
+        ConflictType::SQLITE_CHANGESET_CONSTRAINT => ConflictKind::Constraint,
+        ConflictType::SQLITE_CHANGESET_FOREIGN_KEY => ConflictKind::ForeignKey,
+        _ => return ConflictAction::SQLITE_CHANGESET_ABORT,
+    };
+
+    let conflict = if kind == ConflictKind::ForeignKey {
+        // There's no particular row to speak of in this case.
+        Conflict { table: String::new(), kind, local: Vec::new(), remote: Vec::new() }
+    } else {
+        let Ok(op) = item.op() else {
+            return ConflictAction::SQLITE_CHANGESET_ABORT
+        };
+        let columns = op.number_of_columns() as usize;
+        let local = match kind {
+            ConflictKind::Data | ConflictKind::Conflict => (0..columns)
+                .map(|col| item.conflict(col).map(Value::from).unwrap_or(Value::Null))
+                .collect(),
+            _ => Vec::new(),
+        };
+        let remote = (0..columns)
+            .map_while(|col| match item.new_value(col) {
+                Ok(value) => Some(Some(Value::from(value))),
+                // Columns that an update leaves alone have no new value.
+                Err(rusqlite::Error::InvalidColumnIndex(_)) => Some(None),
+                // Deletes have no new values at all.
+                Err(_) => None,
+            })
+            .collect();
+        Conflict { table: op.table_name().to_owned(), kind, local, remote }
+    };
+
+    match (resolve(conflict), kind) {
+        (Resolution::Abort, _) => ConflictAction::SQLITE_CHANGESET_ABORT,
+        (Resolution::TakeRemote, ConflictKind::Data | ConflictKind::Conflict) => ConflictAction::SQLITE_CHANGESET_REPLACE,
+        (Resolution::TakeRemote | Resolution::KeepLocal, _) => ConflictAction::SQLITE_CHANGESET_OMIT,
+    }
+}
@@ -0,0 +1,752 @@
+use std::collections::BTreeMap;
+
+/// A normalized description of the tables and indexes in a database, used
+/// to check that a database looks the way that we expect it to.
+///
+/// Build one using [`Schema::from_sql`], or read one from an existing
+/// database with [`Schema::read`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct Schema {
+    /// Tables, by name.
+    pub tables: BTreeMap<String, Table>,
+    /// Indexes, by name. Indexes that SQLite creates automatically (for
+    /// `UNIQUE` and `PRIMARY KEY` constraints) are not included.
+    pub indexes: BTreeMap<String, Index>,
+    // Views and triggers, in the order that they were created (so that any which
+    // refer to others come later).
+    pub(crate) dependents: Vec<Dependent>,
+}
+
+// A view or trigger in a [`Schema`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Dependent {
+    pub is_view: bool,
+    pub name: String,
+    // The table that a trigger is on, or the name of a view.
+    pub table: String,
+    // The SQL used to create it, with whitespace normalized.
+    pub sql: String,
+}
+
+impl Dependent {
+    // Might this refer to the given table? Erring on the side of yes is fine.
+    fn refers_to(&self, table: &str) -> bool {
+        self.table.eq_ignore_ascii_case(table)
+            || self.sql.to_lowercase().contains(&table.to_lowercase())
+    }
+
+    // Are these the same view or trigger? Compared like columns are.
+    fn is_same_as(&self, other: &Dependent) -> bool {
+        self.is_view == other.is_view
+            && self.name.eq_ignore_ascii_case(&other.name)
+            && comparable_sql(&self.sql) == comparable_sql(&other.sql)
+    }
+
+    fn drop_sql(&self) -> String {
+        let kind = if self.is_view { "VIEW" } else { "TRIGGER" };
+        format!("DROP {kind} IF EXISTS {}", quote_ident(&self.name))
+    }
+}
+
+/// A table in a [`Schema`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Table {
+    /// The columns of the table, in order.
+    pub columns: Vec<Column>,
+    /// Table constraints, such as `FOREIGN KEY(..) REFERENCES ..`.
+    pub constraints: Vec<String>,
+    /// Table options, such as `STRICT` or `WITHOUT ROWID`.
+    pub options: String,
+    /// The SQL used to create the table, with whitespace normalized.
+    pub sql: String,
+}
+
+/// A column in a [`Table`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Column {
+    /// The name of the column.
+    pub name: String,
+    /// The declared type of the column, uppercased.
+    pub decl_type: String,
+    /// Is the column `NOT NULL`?
+    pub not_null: bool,
+    /// The default value of the column, as written in the schema.
+    pub default: Option<String>,
+    /// 0 if the column is not part of the primary key, else its
+    /// 1-based index into the primary key.
+    pub primary_key: i32,
+    /// The SQL defining the column in the `CREATE TABLE` statement, including
+    /// any column constraints.
+    pub sql: String,
+}
+
+impl Column {
+    // Are the columns the same? Differences in the case and quoting of
+    // identifiers, which SQLite ignores, are ignored here too.
+    fn is_same_as(&self, other: &Column) -> bool {
+        self.name.eq_ignore_ascii_case(&other.name)
+            && self.decl_type == other.decl_type
+            && self.not_null == other.not_null
+            && self.default == other.default
+            && self.primary_key == other.primary_key
+            && comparable_sql(&self.sql) == comparable_sql(&other.sql)
+    }
+}
+
+/// An index in a [`Schema`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Index {
+    /// The table that the index is on.
+    pub table: String,
+    /// Is this a `UNIQUE` index?
+    pub unique: bool,
+    /// The SQL used to create the index, with whitespace normalized.
+    pub sql: String,
+}
+
+impl Index {
+    // Are the indexes the same? Compared like columns are.
+    fn is_same_as(&self, other: &Index) -> bool {
+        self.table.eq_ignore_ascii_case(&other.table)
+            && self.unique == other.unique
+            && comparable_sql(&self.sql) == comparable_sql(&other.sql)
+    }
+}
+
+impl Schema {
+    /// Build a schema from the given SQL (typically some `CREATE TABLE` and
+    /// `CREATE INDEX` statements) by running it against a scratch in-memory
+    /// database and reading the result back.
+    pub fn from_sql(sql: &str) -> Result<Schema, rusqlite::Error> {
+        let conn = rusqlite::Connection::open_in_memory()?;
+        conn.execute_batch(sql)?;
+        Schema::read(&conn)
+    }
+
+    /// Read the schema of the `main` database on the given connection.
+    pub fn read(conn: &rusqlite::Connection) -> Result<Schema, rusqlite::Error> {
+        let mut schema = Schema::default();
+
+        let mut stmt = conn.prepare("
+            SELECT name, sql FROM sqlite_schema
+            WHERE type = 'table' AND name NOT LIKE 'sqlite\\_%' ESCAPE '\\'
+        ")?;
+        let tables = stmt
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut stmt = conn.prepare("
+            SELECT name, type, \"notnull\", dflt_value, pk FROM pragma_table_info(?1)
+        ")?;
+        for (table_name, sql) in tables {
+            let sql = normalize_sql(&sql);
+            let mut columns = stmt.query_map([&table_name], |row| {
+                Ok(Column {
+                    name: row.get(0)?,
+                    decl_type: row.get::<_, String>(1)?.to_uppercase(),
+                    not_null: row.get(2)?,
+                    default: row.get(3)?,
+                    primary_key: row.get(4)?,
+                    sql: String::new(),
+                })
+            })?.collect::<Result<Vec<_>, _>>()?;
+
+            // Split the definitions in the SQL into those for columns and
+            // those which are table constraints.
+            let (defs, options) = table_definitions(&sql);
+            let mut constraints = Vec::new();
+            for def in defs {
+                let ident = leading_ident(def);
+                match columns.iter_mut().find(|c| c.name.eq_ignore_ascii_case(&ident)) {
+                    Some(column) => column.sql = def.to_owned(),
+                    None => constraints.push(def.to_owned()),
+                }
+            }
+            for column in &mut columns {
+                if column.sql.is_empty() {
+                    column.sql = column_sql_from_info(column);
+                }
+            }
+
+            schema.tables.insert(table_name, Table {
+                columns,
+                constraints,
+                options: options.to_owned(),
+                sql
+            });
+        }
+
+        // Automatic indexes have no SQL, so are skipped here.
+        let mut stmt = conn.prepare("
+            SELECT s.name, s.tbl_name, s.sql, l.\"unique\" FROM sqlite_schema AS s
+            JOIN pragma_index_list(s.tbl_name) AS l ON l.name = s.name
+            WHERE s.type = 'index' AND s.sql IS NOT NULL
+        ")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            schema.indexes.insert(row.get(0)?, Index {
+                table: row.get(1)?,
+                sql: normalize_sql(&row.get::<_, String>(2)?),
+                unique: row.get(3)?,
+            });
+        }
+
+        let mut stmt = conn.prepare("
+            SELECT type = 'view', name, tbl_name, sql FROM sqlite_schema
+            WHERE type IN ('view', 'trigger') AND sql IS NOT NULL
+            ORDER BY rowid
+        ")?;
+        schema.dependents = stmt
+            .query_map([], |row| Ok(Dependent {
+                is_view: row.get(0)?,
+                name: row.get(1)?,
+                table: row.get(2)?,
+                sql: normalize_sql(&row.get::<_, String>(3)?),
+            }))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(schema)
+    }
+
+    /// Generate the SQL needed to migrate a database with this schema to the `target`
+    /// schema, for instance to be saved and used as a new migration. See [`migration_sql`].
+    pub fn migration_sql(&self, target: &Schema) -> String {
+        let plan = plan_changes(self, target, true).expect("destructive changes are allowed");
+
+        let mut sql = String::new();
+        if plan.rebuilds_tables {
+            sql.push_str("-- This migration rebuilds tables, so foreign key enforcement must be disabled\n");
+            sql.push_str("-- while it runs; see `Migrations::add_non_transactionally()`.\n");
+        }
+        for stmt in plan.statements {
+            sql.push_str(&stmt);
+            sql.push_str(";\n");
+        }
+        sql
+    }
+
+    /// Work out what differs between this schema and the `expected` one.
+    /// Like SQLite, this ignores the case of keywords and identifiers, and
+    /// whether identifiers are quoted.
+    pub fn diff(&self, expected: &Schema) -> SchemaDiff {
+        let mut diff = SchemaDiff::default();
+
+        for (name, table) in &expected.tables {
+            let Some(actual) = get_named(&self.tables, name) else {
+                diff.missing_tables.push(name.clone());
+                continue
+            };
+            let same_constraints = actual.constraints.len() == table.constraints.len()
+                && actual.constraints.iter().zip(&table.constraints).all(|(a, b)| comparable_sql(a) == comparable_sql(b))
+                && comparable_sql(&actual.options) == comparable_sql(&table.options);
+            if !same_constraints {
+                diff.changed_tables.push(name.clone());
+            }
+            for column in &table.columns {
+                match actual.columns.iter().find(|c| c.name.eq_ignore_ascii_case(&column.name)) {
+                    None => diff.missing_columns.push((name.clone(), column.name.clone())),
+                    Some(c) if !c.is_same_as(column) => diff.changed_columns.push((name.clone(), column.name.clone())),
+                    Some(_) => {}
+                }
+            }
+            for column in &actual.columns {
+                if !table.columns.iter().any(|c| c.name.eq_ignore_ascii_case(&column.name)) {
+                    diff.extra_columns.push((name.clone(), column.name.clone()));
+                }
+            }
+        }
+        for name in self.tables.keys() {
+            if get_named(&expected.tables, name).is_none() {
+                diff.extra_tables.push(name.clone());
+            }
+        }
+
+        for (name, index) in &expected.indexes {
+            match get_named(&self.indexes, name) {
+                None => diff.missing_indexes.push(name.clone()),
+                Some(i) if !i.is_same_as(index) => diff.changed_indexes.push(name.clone()),
+                Some(_) => {}
+            }
+        }
+        for name in self.indexes.keys() {
+            if get_named(&expected.indexes, name).is_none() {
+                diff.extra_indexes.push(name.clone());
+            }
+        }
+
+        diff
+    }
+}
+
+/// The statements needed to bring a database in line with some desired schema.
+pub(crate) struct Plan {
+    /// The statements to run, in order.
+    pub statements: Vec<String>,
+    /// Do any of the statements rebuild tables? If so, foreign key enforcement
+    /// must be disabled while they run.
+    pub rebuilds_tables: bool,
+}
+
+/// Work out the statements needed to bring a database with the `actual` schema in line
+/// with the `desired` one. Tables, columns, indexes, views and triggers are created as
+/// needed, and views and triggers dropped and recreated if they've changed. Dropping
+/// tables or columns, and changing columns (which requires rebuilding the table), is
+/// destructive and only done if `allow_destructive` is true. If the changes can't be
+/// made, the differences between the schemas are handed back.
+pub(crate) fn plan_changes(actual: &Schema, desired: &Schema, allow_destructive: bool) -> Result<Plan, Box<SchemaDiff>> {
+    let diff = actual.diff(desired);
+    let is_destructive = !diff.extra_tables.is_empty()
+        || !diff.extra_columns.is_empty()
+        || !diff.changed_columns.is_empty()
+        || !diff.changed_tables.is_empty();
+    if is_destructive && !allow_destructive {
+        return Err(Box::new(diff))
+    }
+
+    // Columns and constraints can't be changed in place, so tables with changes to
+    // them are rebuilt from scratch, which takes care of any other column changes too.
+    let mut rebuilt_tables: Vec<&str> = diff.changed_tables
+        .iter()
+        .map(|t| t.as_str())
+        .chain(diff.changed_columns.iter().map(|(t, _)| t.as_str()))
+        .collect();
+    rebuilt_tables.sort();
+    rebuilt_tables.dedup();
+
+    let mut stmts = Vec::new();
+
+    // Views and triggers which aren't wanted or have changed are dropped. Renaming a
+    // rebuilt table checks every view and trigger that refers to it, and fails while
+    // the old table is gone, so as SQLite's procedure suggests, those are dropped too.
+    // Dropping columns checks them in the same way, so all of this is done up front.
+    let dropped_dependents: Vec<&Dependent> = actual.dependents
+        .iter()
+        .filter(|d| {
+            !desired.dependents.iter().any(|w| w.is_same_as(d))
+                || rebuilt_tables.iter().any(|t| d.refers_to(t))
+        })
+        .collect();
+    for dependent in dropped_dependents.iter().rev() {
+        stmts.push(dependent.drop_sql());
+    }
+
+    // Indexes only hold derived data, so can always be dropped and recreated.
+    for name in diff.extra_indexes.iter().chain(&diff.changed_indexes) {
+        let index = get_named(&actual.indexes, name).expect("index exists");
+        if !rebuilt_tables.iter().any(|t| t.eq_ignore_ascii_case(&index.table)) {
+            stmts.push(format!("DROP INDEX {}", quote_ident(name)));
+        }
+    }
+    for name in &diff.extra_tables {
+        stmts.push(format!("DROP TABLE {}", quote_ident(name)));
+    }
+    for name in &diff.missing_tables {
+        stmts.push(desired.tables[name].sql.clone());
+    }
+    for (table_name, column_name) in &diff.missing_columns {
+        if rebuilt_tables.contains(&table_name.as_str()) {
+            continue
+        }
+        let column = desired.tables[table_name].columns.iter().find(|c| &c.name == column_name).expect("column exists");
+        stmts.push(format!("ALTER TABLE {} ADD COLUMN {}", quote_ident(table_name), column.sql));
+    }
+    for (table_name, column_name) in &diff.extra_columns {
+        if rebuilt_tables.contains(&table_name.as_str()) {
+            continue
+        }
+        stmts.push(format!("ALTER TABLE {} DROP COLUMN {}", quote_ident(table_name), quote_ident(column_name)));
+    }
+    for &table_name in &rebuilt_tables {
+        let old = get_named(&actual.tables, table_name).expect("table exists");
+        rebuild_table(&mut stmts, table_name, old, &desired.tables[table_name]);
+    }
+    for (name, index) in &desired.indexes {
+        let is_new = diff.missing_indexes.contains(name) || diff.changed_indexes.contains(name);
+        if is_new || rebuilt_tables.iter().any(|t| t.eq_ignore_ascii_case(&index.table)) {
+            stmts.push(index.sql.clone());
+        }
+    }
+    // Views and triggers are (re)created once all of the tables they might refer to are in place.
+    for dependent in &desired.dependents {
+        let exists = actual.dependents.iter().any(|d| d.is_same_as(dependent));
+        let was_dropped = dropped_dependents.iter().any(|d| d.name.eq_ignore_ascii_case(&dependent.name));
+        if !exists || was_dropped {
+            stmts.push(dependent.sql.clone());
+        }
+    }
+
+    Ok(Plan { statements: stmts, rebuilds_tables: !rebuilt_tables.is_empty() })
+}
+
+// The statements needed to rebuild a table with a new definition, copying across the
+// data in any columns that the old and new definitions have in common. Indexes and
+// triggers on the table are dropped along with it, and views and triggers referring
+// to it must be dropped beforehand; all of these need recreating afterwards. See
+// "Making Other Kinds Of Table Schema Changes" at <https://www.sqlite.org/lang_altertable.html>.
+fn rebuild_table(stmts: &mut Vec<String>, name: &str, old: &Table, new: &Table) {
+    let tmp_name = format!("{name}_sqliter_new");
+
+    // Swap the name in the new definition for the temporary one.
+    let (body_start, _) = unquoted_chars(&new.sql)
+        .find(|&(_, c)| c == '(')
+        .expect("CREATE TABLE statement should contain a '('");
+    stmts.push(format!("CREATE TABLE {}{}", quote_ident(&tmp_name), &new.sql[body_start..]));
+
+    let common_columns = new.columns
+        .iter()
+        .filter(|c| old.columns.iter().any(|o| o.name.eq_ignore_ascii_case(&c.name)))
+        .map(|c| quote_ident(&c.name))
+        .collect::<Vec<_>>()
+        .join(",");
+    stmts.push(format!(
+        "INSERT INTO {}({common_columns}) SELECT {common_columns} FROM {}",
+        quote_ident(&tmp_name),
+        quote_ident(name)
+    ));
+    stmts.push(format!("DROP TABLE {}", quote_ident(name)));
+    stmts.push(format!("ALTER TABLE {} RENAME TO {}", quote_ident(&tmp_name), quote_ident(name)));
+}
+
+// Build the SQL defining a column from what `pragma_table_info` tells us about it.
+// This is used if we can't find the definition in the `CREATE TABLE` statement.
+fn column_sql_from_info(column: &Column) -> String {
+    let mut def = quote_ident(&column.name);
+    if !column.decl_type.is_empty() {
+        def.push(' ');
+        def.push_str(&column.decl_type);
+    }
+    if column.not_null {
+        def.push_str(" NOT NULL");
+    }
+    if let Some(default) = &column.default {
+        def.push_str(" DEFAULT ");
+        def.push_str(default);
+    }
+    def
+}
+
+// Split the body of a `CREATE TABLE` statement into its column definitions
+// and table constraints, also returning any table options after the body.
+fn table_definitions(table_sql: &str) -> (Vec<&str>, &str) {
+    let mut defs = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (idx, c) in unquoted_chars(table_sql) {
+        match c {
+            '(' => {
+                depth += 1;
+                if depth == 1 {
+                    start = idx + 1;
+                }
+            },
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    defs.push(table_sql[start..idx].trim());
+                    return (defs, table_sql[idx + 1..].trim())
+                }
+            },
+            ',' if depth == 1 => {
+                defs.push(table_sql[start..idx].trim());
+                start = idx + 1;
+            },
+            _ => {}
+        }
+    }
+    (defs, "")
+}
+
+// The identifier at the start of some SQL, with any quotes removed.
+fn leading_ident(sql: &str) -> String {
+    let sql = sql.trim_start();
+    let Some(first) = sql.chars().next() else {
+        return String::new()
+    };
+    let close = match first {
+        '"' => '"',
+        '`' => '`',
+        '[' => ']',
+        _ => return sql.split(|c: char| c.is_whitespace()).next().unwrap_or_default().to_owned()
+    };
+    let mut ident = String::new();
+    let mut chars = sql[1..].chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == close {
+            // Doubled quotes are escaped quotes.
+            if close != ']' && chars.peek() == Some(&close) {
+                chars.next();
+            } else {
+                break
+            }
+        }
+        ident.push(c);
+    }
+    ident
+}
+
+// Look up a table or index by name. Like SQLite, this ignores case if there's no exact match.
+fn get_named<'a, T>(items: &'a BTreeMap<String, T>, name: &str) -> Option<&'a T> {
+    items.get(name).or_else(|| {
+        items.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, item)| item)
+    })
+}
+
+// Some normalized SQL in a form that can be compared to other SQL: quoted identifiers
+// are unquoted, and everything but string literals is lowercased, since SQLite ignores
+// the case of keywords and identifiers.
+fn comparable_sql(sql: &str) -> String {
+    let mut out = String::with_capacity(sql.len());
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        let close = match c {
+            '\'' | '"' | '`' => c,
+            '[' => ']',
+            _ => {
+                out.extend(c.to_lowercase());
+                continue
+            }
+        };
+        let is_string = c == '\'';
+        if is_string {
+            out.push(c);
+        }
+        while let Some(c) = chars.next() {
+            if c == close {
+                // Doubled quotes are escaped quotes.
+                if close != ']' && chars.peek() == Some(&close) {
+                    chars.next();
+                    if is_string {
+                        out.push(c);
+                    }
+                } else {
+                    break
+                }
+            }
+            if is_string {
+                out.push(c);
+            } else {
+                out.extend(c.to_lowercase());
+            }
+        }
+        if is_string {
+            out.push(close);
+        }
+    }
+    out
+}
+
+// Iterate over the characters (and their byte offsets) in some SQL that
+// are not inside string literals or quoted identifiers.
+fn unquoted_chars(sql: &str) -> impl Iterator<Item = (usize, char)> + '_ {
+    let mut quote = None;
+    sql.char_indices().filter(move |&(_, c)| {
+        match quote {
+            Some(q) => {
+                if c == q {
+                    quote = None;
+                }
+                false
+            },
+            None => {
+                quote = match c {
+                    '\'' | '"' | '`' => Some(c),
+                    '[' => Some(']'),
+                    _ => None
+                };
+                quote.is_none()
+            }
+        }
+    })
+}
+
+// Quote an identifier so that it can be used in SQL.
+pub(crate) fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Compare the schemas of the `main` databases on two connections, and generate the SQL
+/// needed to migrate a database from the schema of `from` to the schema of `to`. Typically
+/// `from` has had all of the current migrations applied, and `to` has the desired schema.
+/// The output can then be used as the next migration.
+///
+/// Tables, columns and indexes are created, changed and dropped as needed. Changes to
+/// existing columns that `ALTER TABLE` can't express are made by rebuilding the table
+/// and copying the data across. **Foreign key enforcement must be disabled when applying
+/// a migration that rebuilds tables**, else dropping the old table may cascade to (or be
+/// blocked by) rows referencing it. Such migrations are noted with a comment at the top,
+/// and should be applied using [`crate::Migrations::add_non_transactionally`] like so:
+///
+/// ```rust
+/// # fn f(migration_sql: &'static str) -> sqliter::Migrations {
+/// sqliter::Migrations::new().add_non_transactionally(2, move |conn| {
+///     conn.pragma_update(None, "foreign_keys", false)?;
+///     let tx = conn.unchecked_transaction()?;
+///     tx.execute_batch(migration_sql)?;
+///     // Check that foreign keys are all still valid before committing.
+///     if tx.prepare("PRAGMA foreign_key_check")?.exists([])? {
+///         return Err(rusqlite::Error::InvalidQuery);
+///     }
+///     tx.commit()?;
+///     conn.pragma_update(None, "foreign_keys", true)
+/// })
+/// # }
+/// ```
+///
+/// Views and triggers are created, dropped and recreated to match `to`. Any which refer
+/// to a rebuilt table are also dropped before it's rebuilt and recreated afterwards.
+pub fn migration_sql(from: &rusqlite::Connection, to: &rusqlite::Connection) -> Result<String, rusqlite::Error> {
+    Ok(Schema::read(from)?.migration_sql(&Schema::read(to)?))
+}
+
+/// Produce a normalized text dump of the schema of the `main` database on the given
+/// connection, which is suitable for comparing against in tests (for instance, to
+/// check what the schema looks like after all migrations have been applied).
+///
+/// The dump contains the `application_id` and `user_version`, followed by the
+/// statements used to create every table, index, trigger and view. These are
+/// sorted by type and then name, and have their whitespace normalized, so that
+/// the output is deterministic.
+pub fn dump_schema(conn: &rusqlite::Connection) -> Result<String, rusqlite::Error> {
+    let app_id: i32 = conn.pragma_query_value(None, "application_id", |row| row.get(0))?;
+    let user_version: i32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+    let mut out = format!("PRAGMA application_id = {app_id};\nPRAGMA user_version = {user_version};\n");
+
+    let mut stmt = conn.prepare("
+        SELECT type, sql FROM sqlite_schema
+        WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite\\_%' ESCAPE '\\'
+        ORDER BY CASE type
+            WHEN 'table' THEN 0
+            WHEN 'index' THEN 1
+            WHEN 'trigger' THEN 2
+            ELSE 3
+        END, name
+    ")?;
+    let mut rows = stmt.query([])?;
+    let mut last_ty = String::new();
+    while let Some(row) = rows.next()? {
+        let ty: String = row.get(0)?;
+        if ty != last_ty {
+            out.push('\n');
+            last_ty = ty;
+        }
+        out.push_str(&normalize_sql(&row.get::<_, String>(1)?));
+        out.push_str(";\n");
+    }
+
+    Ok(out)
+}
+
+/// The differences between an actual and an expected [`Schema`]. See [`Schema::diff`].
+/// "Missing" things are expected but not present, and "extra" things are present
+/// but not expected. Columns are given as `(table, column)`. "Changed" tables have
+/// different table constraints or options.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct SchemaDiff {
+    pub missing_tables: Vec<String>,
+    pub extra_tables: Vec<String>,
+    pub missing_columns: Vec<(String, String)>,
+    pub extra_columns: Vec<(String, String)>,
+    pub changed_columns: Vec<(String, String)>,
+    pub changed_tables: Vec<String>,
+    pub missing_indexes: Vec<String>,
+    pub extra_indexes: Vec<String>,
+    pub changed_indexes: Vec<String>,
+}
+
+impl SchemaDiff {
+    /// Returns true if there are no differences.
+    pub fn is_empty(&self) -> bool {
+        self == &SchemaDiff::default()
+    }
+}
+
+impl std::fmt::Display for SchemaDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let tables = [
+            ("missing tables", &self.missing_tables),
+            ("extra tables", &self.extra_tables),
+            ("changed tables", &self.changed_tables),
+            ("missing indexes", &self.missing_indexes),
+            ("extra indexes", &self.extra_indexes),
+            ("changed indexes", &self.changed_indexes),
+        ];
+        let columns = [
+            ("missing columns", &self.missing_columns),
+            ("extra columns", &self.extra_columns),
+            ("changed columns", &self.changed_columns),
+        ];
+
+        let mut parts = Vec::new();
+        for (desc, names) in tables {
+            if !names.is_empty() {
+                parts.push(format!("{desc}: {}", names.join(", ")));
+            }
+        }
+        for (desc, names) in columns {
+            if !names.is_empty() {
+                let names: Vec<_> = names.iter().map(|(t, c)| format!("{t}.{c}")).collect();
+                parts.push(format!("{desc}: {}", names.join(", ")));
+            }
+        }
+
+        if parts.is_empty() {
+            write!(f, "no differences")
+        } else {
+            write!(f, "{}", parts.join("; "))
+        }
+    }
+}
+
+// Collapse whitespace in some SQL so that formatting differences don't matter.
+// Runs of whitespace become a single space, except next to brackets and commas
+// where it's removed entirely. Comments are treated as whitespace, since they'd
+// otherwise swallow whatever follows them once newlines are gone. Quoted strings
+// and identifiers are left alone.
+pub(crate) fn normalize_sql(sql: &str) -> String {
+    let mut out = String::with_capacity(sql.len());
+    let mut quote = None;
+    let mut pending_space = false;
+    let mut chars = sql.trim().chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if let Some(q) = quote {
+            out.push(c);
+            if c == q {
+                quote = None;
+            }
+            continue
+        }
+        if c == '-' && chars.peek() == Some(&'-') {
+            chars.find(|&c| c == '\n');
+            pending_space = true;
+            continue
+        }
+        if c == '/' && chars.peek() == Some(&'*') {
+            chars.next();
+            let mut last = None;
+            chars.find(|&c| last.replace(c) == Some('*') && c == '/');
+            pending_space = true;
+            continue
+        }
+        if c.is_whitespace() {
+            pending_space = true;
+            continue
+        }
+        if pending_space && !out.is_empty() && !matches!(c, '(' | ')' | ',') && !out.ends_with(['(', ',']) {
+            out.push(' ');
+        }
+        pending_space = false;
+        if matches!(c, '\'' | '"' | '`' | '[') {
+            quote = Some(if c == '[' { ']' } else { c });
+        }
+        out.push(c);
+    }
+
+    out
+}
@@ -0,0 +1,10 @@
+use async_rusqlite::Connection;
+
+/// Serialize the `main` database on the given connection to bytes, which can be
+/// turned back into a database with [`crate::ConnectionBuilder::open_from_bytes`].
+/// This works for databases stored in files as well as in-memory ones.
+pub async fn serialize(conn: &Connection) -> Result<Vec<u8>, rusqlite::Error> {
+    conn.call(|conn| {
+        conn.serialize(rusqlite::MAIN_DB).map(|data| data.to_vec())
+    }).await
+}
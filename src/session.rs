@@ -0,0 +1,270 @@
+use async_rusqlite::Connection;
+use rusqlite::ErrorCode;
+use rusqlite::session::{ ConflictAction, Session, invert_strm };
+use crate::error::SessionError;
+use crate::schema::quote_ident;
+
+/// Undo and redo changes to a database, using the SQLite session extension.
+///
+/// Changes are recorded one logical operation at a time via [`UndoStack::record`],
+/// and each operation can then be undone and redone as a unit. Only tables in the
+/// `main` database which have a primary key are tracked; see
+/// <https://sqlite.org/sessionintro.html#limitations>.
+///
+/// Undoing or redoing an operation fails with [`SessionError::Conflict`] (and leaves
+/// the database untouched) if the rows it affects have since been changed in some
+/// other way than through this stack.
+///
+/// ```rust
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use sqliter::{ ConnectionBuilder, UndoStack };
+///
+/// let conn = ConnectionBuilder::new()
+///     .add_migration(1, |conn| conn.execute_batch("
+///         CREATE TABLE note (id INTEGER PRIMARY KEY, text TEXT NOT NULL);
+///     "))
+///     .open_in_memory()
+///     .await?;
+///
+/// let mut history = UndoStack::new().limit(100);
+/// history.record(&conn, |conn| {
+///     conn.execute("INSERT INTO note (text) VALUES ('hello')", [])
+/// }).await?;
+///
+/// assert!(history.undo(&conn).await?);
+/// assert!(history.redo(&conn).await?);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct UndoStack {
+    // Changesets to apply to undo or redo each operation, most recent last.
+    undo: Vec<Vec<u8>>,
+    redo: Vec<Vec<u8>>,
+    limit: Option<usize>,
+    // Where the stack is persisted, if anywhere.
+    table: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Stack {
+    Undo,
+    Redo,
+}
+
+impl UndoStack {
+    /// Create a new, empty undo stack, which is held only in memory.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only keep the most recent `operations` operations around to be undone.
+    pub fn limit(mut self, operations: usize) -> Self {
+        self.limit = Some(operations);
+        self
+    }
+
+    /// Persist the stack in the given table, so that operations can still be undone
+    /// after the database is reopened. The table is created if it doesn't exist, as:
+    ///
+    /// ```sql
+    /// CREATE TABLE <table> (id INTEGER PRIMARY KEY, redo INTEGER NOT NULL, changeset BLOB NOT NULL)
+    /// ```
+    ///
+    /// Any operations already in the table are loaded, replacing those in this stack,
+    /// and the oldest are dropped if there are more than [`UndoStack::limit`] allows.
+    /// Changes to the table itself are never recorded. If you use
+    /// [`crate::ConnectionBuilder::verify_schema`] or [`crate::ConnectionBuilder::schema`],
+    /// the table should be included in the schema given to them.
+    pub async fn persist(mut self, conn: &Connection, table: &str) -> Result<Self, SessionError> {
+        let table = table.to_owned();
+        let t = table.clone();
+        let limit = self.limit;
+        let (undo, redo) = conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(&format!(
+                "CREATE TABLE IF NOT EXISTS {} (id INTEGER PRIMARY KEY, redo INTEGER NOT NULL, changeset BLOB NOT NULL)",
+                quote_ident(&t)
+            ), [])?;
+            let mut stmt = tx.prepare(&format!("SELECT changeset FROM {} WHERE redo = ?1 ORDER BY id", quote_ident(&t)))?;
+            let mut undo = stmt.query_map([false], |row| row.get(0))?.collect::<Result<Vec<Vec<u8>>, _>>()?;
+            let redo = stmt.query_map([true], |row| row.get(0))?.collect::<Result<Vec<Vec<u8>>, _>>()?;
+            drop(stmt);
+
+            // The table may have been written with a higher limit, or none at all.
+            let excess = limit.map_or(0, |limit| undo.len().saturating_sub(limit));
+            trim_table(&tx, &t, excess)?;
+            undo.drain(..excess);
+            tx.commit()?;
+            Ok::<_, SessionError>((undo, redo))
+        }).await?;
+
+        self.undo = undo;
+        self.redo = redo;
+        self.table = Some(table);
+        Ok(self)
+    }
+
+    /// Run `f` in a transaction, recording the changes that it makes as one operation
+    /// which can be undone. If `f` returns an error, the transaction is rolled back and
+    /// nothing is recorded. Recording an operation that changes anything clears the
+    /// redo stack.
+    pub async fn record<F, R>(&mut self, conn: &Connection, f: F) -> Result<R, SessionError>
+    where
+        F: FnOnce(&rusqlite::Connection) -> Result<R, rusqlite::Error> + Send + 'static,
+        R: Send + 'static
+    {
+        let table = self.table.clone();
+        // How many of the oldest operations to drop to stay within the limit.
+        let excess = self.limit.map_or(0, |limit| (self.undo.len() + 1).saturating_sub(limit));
+
+        let (res, inverse) = conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            let ignore: Vec<String> = table.iter().cloned().collect();
+            let (res, changeset) = record_changes(&tx, ignore, f)?;
+
+            // Nothing changed, so there's nothing to undo.
+            if changeset.is_empty() {
+                tx.commit()?;
+                return Ok((res, None))
+            }
+
+            let inverse = invert(&changeset)?;
+            if let Some(table) = &table {
+                clear_table(&tx, table, Some(Stack::Redo))?;
+                push_table(&tx, table, Stack::Undo, &inverse)?;
+                trim_table(&tx, table, excess)?;
+            }
+            tx.commit()?;
+            Ok::<_, SessionError>((res, Some(inverse)))
+        }).await?;
+
+        if let Some(inverse) = inverse {
+            self.redo.clear();
+            self.undo.push(inverse);
+            self.undo.drain(..excess.min(self.undo.len()));
+        }
+        Ok(res)
+    }
+
+    /// Undo the most recently recorded (or redone) operation, returning false if there
+    /// was nothing to undo.
+    pub async fn undo(&mut self, conn: &Connection) -> Result<bool, SessionError> {
+        self.apply(conn, Stack::Undo).await
+    }
+
+    /// Redo the most recently undone operation, returning false if there was nothing
+    /// to redo.
+    pub async fn redo(&mut self, conn: &Connection) -> Result<bool, SessionError> {
+        self.apply(conn, Stack::Redo).await
+    }
+
+    /// Is there anything to undo?
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    /// Is there anything to redo?
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Forget every operation, so that nothing can be undone or redone.
+    pub async fn clear(&mut self, conn: &Connection) -> Result<(), SessionError> {
+        if let Some(table) = self.table.clone() {
+            conn.call(move |conn| clear_table(conn, &table, None)).await?;
+        }
+        self.undo.clear();
+        self.redo.clear();
+        Ok(())
+    }
+
+    // Apply the changeset at the top of one stack, and push its inverse onto the other.
+    async fn apply(&mut self, conn: &Connection, from: Stack) -> Result<bool, SessionError> {
+        let (from_stack, to_stack) = match from {
+            Stack::Undo => (&mut self.undo, &mut self.redo),
+            Stack::Redo => (&mut self.redo, &mut self.undo),
+        };
+        let Some(changeset) = from_stack.last().cloned() else {
+            return Ok(false)
+        };
+        let to = match from { Stack::Undo => Stack::Redo, Stack::Redo => Stack::Undo };
+        let table = self.table.clone();
+
+        let inverse = conn.call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.apply_strm(
+                &mut &*changeset,
+                None::<fn(&str) -> bool>,
+                |_conflict, _item| ConflictAction::SQLITE_CHANGESET_ABORT
+            ).map_err(|e| match e.sqlite_error_code() {
+                Some(ErrorCode::OperationAborted) => SessionError::Conflict,
+                _ => e.into()
+            })?;
+
+            let inverse = invert(&changeset)?;
+            if let Some(table) = &table {
+                tx.execute(&format!(
+                    "DELETE FROM {0} WHERE id = (SELECT max(id) FROM {0} WHERE redo = ?1)",
+                    quote_ident(table)
+                ), [matches!(from, Stack::Redo)])?;
+                push_table(&tx, table, to, &inverse)?;
+            }
+            tx.commit()?;
+            Ok::<_, SessionError>(inverse)
+        }).await?;
+
+        from_stack.pop();
+        to_stack.push(inverse);
+        Ok(true)
+    }
+}
+
+// Run `f`, returning the changes it made to every table in the main database except
+// those given, as a changeset.
+pub(crate) fn record_changes<F, R>(conn: &rusqlite::Connection, ignore: Vec<String>, f: F) -> Result<(R, Vec<u8>), rusqlite::Error>
+where
+    F: FnOnce(&rusqlite::Connection) -> Result<R, rusqlite::Error>
+{
+    let mut session = Session::new(conn)?;
+    session.table_filter(Some(move |t: &str| !ignore.iter().any(|i| i == t)));
+    session.attach(None::<&str>)?;
+    let res = f(conn)?;
+    let mut changeset = Vec::new();
+    session.changeset_strm(&mut changeset)?;
+    Ok((res, changeset))
+}
+
+// Invert a changeset, so that applying it undoes the changes.
+fn invert(changeset: &[u8]) -> Result<Vec<u8>, rusqlite::Error> {
+    let mut inverse = Vec::new();
+    invert_strm(&mut &*changeset, &mut inverse)?;
+    Ok(inverse)
+}
+
+fn push_table(conn: &rusqlite::Connection, table: &str, stack: Stack, changeset: &[u8]) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        &format!("INSERT INTO {} (redo, changeset) VALUES (?1, ?2)", quote_ident(table)),
+        (matches!(stack, Stack::Redo), changeset)
+    )?;
+    Ok(())
+}
+
+// Remove the oldest `excess` operations from the undo stack.
+fn trim_table(conn: &rusqlite::Connection, table: &str, excess: usize) -> Result<(), rusqlite::Error> {
+    conn.execute(&format!(
+        "DELETE FROM {0} WHERE id IN (SELECT id FROM {0} WHERE redo = 0 ORDER BY id LIMIT ?1)",
+        quote_ident(table)
+    ), [excess])?;
+    Ok(())
+}
+
+// Remove every operation from one stack, or from both if no stack is given.
+fn clear_table(conn: &rusqlite::Connection, table: &str, stack: Option<Stack>) -> Result<(), rusqlite::Error> {
+    match stack {
+        Some(stack) => conn.execute(&format!("DELETE FROM {} WHERE redo = ?1", quote_ident(table)), [matches!(stack, Stack::Redo)])?,
+        None => conn.execute(&format!("DELETE FROM {}", quote_ident(table)), [])?,
+    };
+    Ok(())
+}
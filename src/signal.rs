@@ -0,0 +1,56 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{ Arc, Mutex };
+use std::task::{ Context, Poll, Waker };
+
+/// Create a runtime agnostic one-shot signal. The [`Wait`] future
+/// completes once [`Notify::notify`] is called.
+pub(crate) fn signal() -> (Notify, Wait) {
+    let state = Arc::new(Mutex::new(State { done: false, waker: None }));
+    (Notify(state.clone()), Wait(state))
+}
+
+struct State {
+    done: bool,
+    waker: Option<Waker>,
+}
+
+pub(crate) struct Notify(Arc<Mutex<State>>);
+
+impl Notify {
+    pub fn notify(self) {
+        let mut state = self.0.lock().unwrap();
+        state.done = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl std::fmt::Debug for Notify {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Notify").finish_non_exhaustive()
+    }
+}
+
+pub(crate) struct Wait(Arc<Mutex<State>>);
+
+impl std::fmt::Debug for Wait {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Wait").finish_non_exhaustive()
+    }
+}
+
+impl Future for Wait {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.0.lock().unwrap();
+        if state.done {
+            Poll::Ready(())
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::BTreeSet;
+use std::path::{ Path, PathBuf };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use async_rusqlite::Connection;
+
+use crate::error::SnapshotError;
+use crate::maintenance::MaintenanceTask;
+use crate::timer;
+
+const PREFIX: &str = "snapshot-";
+const SUFFIX: &str = ".db";
+
+/// Take regular snapshots of a database using `VACUUM INTO`, keeping some of them
+/// according to a grandfather-father-son retention policy. Use
+/// [`crate::ConnectionBuilder::snapshots`] or [`Snapshots::task`] to get hold of a
+/// [`MaintenanceTask`] which takes them.
+///
+/// Snapshots are written to their own directory, and can be found again using
+/// [`list_snapshots()`] and restored using [`crate::ConnectionBuilder::restore_snapshot`].
+///
+/// ```rust
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::time::Duration;
+/// use sqliter::{ ConnectionBuilder, Snapshots };
+/// # let dir = tempfile::tempdir()?;
+/// # let path = dir.path().join("my-app.db");
+/// # let snapshot_dir = dir.path().join("snapshots");
+///
+/// let opened = ConnectionBuilder::<rusqlite::Error>::new()
+///     .snapshots(
+///         Snapshots::new(snapshot_dir)
+///             .every(Duration::from_secs(60 * 60))
+///             .keep(24, 7, 4)
+///     )
+///     .open_detailed(path)
+///     .await?;
+///
+/// tokio::spawn(opened.snapshots.unwrap());
+/// # Ok(())
+/// # }
+/// ```
+pub struct Snapshots {
+    dir: PathBuf,
+    interval: Duration,
+    keep: (usize, usize, usize),
+    on_result: Option<Box<OnResultFn>>,
+}
+
+type OnResultFn = dyn FnMut(Result<Snapshot, SnapshotError>) + Send + 'static;
+
+/// A snapshot of a database. See [`Snapshots`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Snapshot {
+    /// Where the snapshot is.
+    pub path: PathBuf,
+    /// When the snapshot was taken.
+    pub taken: SystemTime,
+}
+
+impl Snapshots {
+    /// Configure snapshots to be saved in the given directory, which will be created if
+    /// necessary. By default, a snapshot is taken every hour, and 24 hourly, 7 daily
+    /// and 4 weekly snapshots are kept.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Snapshots {
+            dir: dir.into(),
+            interval: Duration::from_secs(60 * 60),
+            keep: (24, 7, 4),
+            on_result: None,
+        }
+    }
+
+    /// Take a snapshot at the given interval.
+    pub fn every(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// How many snapshots to keep. The newest snapshot from each of the last `hourly`
+    /// hours that have any is kept, and likewise for the last `daily` days and `weekly`
+    /// weeks. The newest snapshot is always kept, and the rest are deleted.
+    pub fn keep(mut self, hourly: usize, daily: usize, weekly: usize) -> Self {
+        self.keep = (hourly, daily, weekly);
+        self
+    }
+
+    /// Called with the result of each attempt to take a snapshot.
+    pub fn on_result<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(Result<Snapshot, SnapshotError>) + Send + 'static
+    {
+        self.on_result = Some(Box::new(f));
+        self
+    }
+
+    /// Return a task which takes snapshots of the given connection. This must be
+    /// spawned or otherwise polled to completion for anything to happen.
+    ///
+    /// The task holds onto the connection, and so keeps it open until either the task
+    /// is dropped, or the connection is explicitly closed via [`Connection::close`].
+    pub fn task(self, conn: Connection) -> MaintenanceTask {
+        MaintenanceTask::new(self.run(conn))
+    }
+
+    async fn run(mut self, conn: Connection) {
+        loop {
+            timer::sleep(self.interval).await;
+
+            let dir = self.dir.clone();
+            let keep = self.keep;
+            let res = conn.call(move |conn| take_snapshot(conn, &dir, keep)).await;
+
+            if let Err(SnapshotError::UnexpectedlyClosed) = res {
+                return
+            }
+            if let Some(on_result) = &mut self.on_result {
+                on_result(res);
+            }
+        }
+    }
+}
+
+/// List the snapshots in a directory that [`Snapshots`] saves them to, oldest first.
+pub fn list_snapshots<P: AsRef<Path>>(dir: P) -> std::io::Result<Vec<Snapshot>> {
+    let mut snapshots = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let millis = path.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(PREFIX)?.strip_suffix(SUFFIX)?.parse().ok());
+        if let Some(millis) = millis {
+            snapshots.push(Snapshot { path, taken: UNIX_EPOCH + Duration::from_millis(millis) });
+        }
+    }
+    snapshots.sort_by_key(|s| s.taken);
+    Ok(snapshots)
+}
+
+// Take a snapshot, and then remove any that we no longer need to keep.
+fn take_snapshot(conn: &rusqlite::Connection, dir: &Path, keep: (usize, usize, usize)) -> Result<Snapshot, SnapshotError> {
+    std::fs::create_dir_all(dir)?;
+
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    let taken = UNIX_EPOCH + Duration::from_millis(millis);
+    let path = dir.join(format!("{PREFIX}{millis}{SUFFIX}"));
+
+    // Write to a temporary file first so that partial snapshots are never listed.
+    let mut temp_path = path.clone().into_os_string();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    let _ = std::fs::remove_file(&temp_path);
+
+    let temp_str = temp_path.to_str().ok_or_else(|| rusqlite::Error::InvalidPath(temp_path.clone()))?;
+    conn.execute("VACUUM INTO ?1", [temp_str])?;
+    std::fs::rename(&temp_path, &path)?;
+
+    let snapshots = list_snapshots(dir)?;
+    for snapshot in expired(&snapshots, keep) {
+        std::fs::remove_file(&snapshot.path)?;
+    }
+
+    Ok(Snapshot { path, taken })
+}
+
+// Given snapshots ordered oldest first, return those which the retention policy
+// doesn't keep. For each period, the newest snapshot in each of the most recent
+// `count` periods with snapshots in is kept.
+pub(crate) fn expired(snapshots: &[Snapshot], (hourly, daily, weekly): (usize, usize, usize)) -> Vec<&Snapshot> {
+    const HOUR: u64 = 60 * 60;
+    let secs = |s: &Snapshot| s.taken.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+    let mut keep = BTreeSet::new();
+    keep.insert(snapshots.len().saturating_sub(1));
+
+    for (period, count) in [(HOUR, hourly), (24 * HOUR, daily), (7 * 24 * HOUR, weekly)] {
+        let mut last_bucket = None;
+        let mut buckets = 0;
+        for (idx, snapshot) in snapshots.iter().enumerate().rev() {
+            let bucket = secs(snapshot) / period;
+            if last_bucket == Some(bucket) {
+                continue
+            }
+            if buckets == count {
+                break
+            }
+            last_bucket = Some(bucket);
+            buckets += 1;
+            keep.insert(idx);
+        }
+    }
+
+    snapshots.iter()
+        .enumerate()
+        .filter(|(idx, _)| !keep.contains(idx))
+        .map(|(_, s)| s)
+        .collect()
+}
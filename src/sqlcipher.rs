@@ -0,0 +1,11 @@
+use async_rusqlite::Connection;
+
+/// Change the SQLCipher key of an open database, re-encrypting it with the
+/// new key. The database must have been opened with the current key via
+/// [`crate::ConnectionBuilder::key`].
+pub async fn rekey<S: Into<String>>(conn: &Connection, secret: S) -> Result<(), rusqlite::Error> {
+    let secret = secret.into();
+    conn.call(move |conn| {
+        conn.pragma_update(None, "rekey", secret)
+    }).await
+}
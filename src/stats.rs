@@ -0,0 +1,49 @@
+use async_rusqlite::Connection;
+
+/// Statistics about a database, useful for diagnostics. See [`stats()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Stats {
+    /// The size of each page in bytes.
+    pub page_size: u64,
+    /// The total number of pages in the database.
+    pub page_count: u64,
+    /// The number of unused pages in the database.
+    pub freelist_count: u64,
+    /// The size of the database file in bytes, or `None` for in-memory databases.
+    pub file_size: Option<u64>,
+    /// The size of the `-wal` file in bytes, or `None` if there isn't one.
+    pub wal_size: Option<u64>,
+    /// The journal mode, for example `"wal"` or `"delete"`.
+    pub journal_mode: String,
+    /// The `user_version`, which tracks the migrations that have been applied.
+    pub user_version: i32,
+    /// The `application_id`.
+    pub application_id: i32,
+    /// The version of the SQLite library in use.
+    pub sqlite_version: String,
+}
+
+/// Gather some statistics about the `main` database on the given connection.
+pub async fn stats(conn: &Connection) -> Result<Stats, rusqlite::Error> {
+    conn.call(|conn| {
+        let pragma_u64 = |name: &str| conn.pragma_query_value(None, name, |row| row.get::<_, u64>(0));
+        let pragma_i32 = |name: &str| conn.pragma_query_value(None, name, |row| row.get::<_, i32>(0));
+
+        // In-memory databases have an empty path.
+        let path = conn.path().filter(|p| !p.is_empty());
+        let file_size = |path: String| std::fs::metadata(path).ok().map(|m| m.len());
+
+        Ok(Stats {
+            page_size: pragma_u64("page_size")?,
+            page_count: pragma_u64("page_count")?,
+            freelist_count: pragma_u64("freelist_count")?,
+            file_size: path.and_then(|p| file_size(p.to_owned())),
+            wal_size: path.and_then(|p| file_size(format!("{p}-wal"))),
+            journal_mode: conn.pragma_query_value(None, "journal_mode", |row| row.get(0))?,
+            user_version: pragma_i32("user_version")?,
+            application_id: pragma_i32("application_id")?,
+            sqlite_version: rusqlite::version().to_owned(),
+        })
+    }).await
+}
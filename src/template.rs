@@ -0,0 +1,63 @@
+use async_rusqlite::Connection;
+use crate::builder::ConnectionBuilder;
+use crate::error::ConnectionBuilderError;
+use crate::serialize::serialize;
+
+type BuilderFn<E> = dyn Fn() -> ConnectionBuilder<E> + Send + Sync + 'static;
+
+/// A database which has been migrated once, and which cheap, independent
+/// in-memory copies can then be opened from. This is useful in tests, where
+/// replaying every migration for each test can be slow.
+///
+/// The template is given a function which returns a [`ConnectionBuilder`]. This
+/// is called once to create and migrate the template database, and then again
+/// for each copy, so that copies are opened with the same configuration (and
+/// get their own [`ConnectionBuilder::on_close`] function, for instance). Since
+/// the copies are already up to date, no migrations need to run for them. Note
+/// that the template's own connection is closed once it's been created, so any
+/// `on_close` function is called for it too.
+///
+/// ```rust
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use sqliter::{ ConnectionBuilder, Template };
+///
+/// let template = Template::new(|| {
+///     ConnectionBuilder::new()
+///         .add_migration(1, |conn| conn.execute_batch("CREATE TABLE user (id INTEGER PRIMARY KEY)"))
+/// }).await?;
+///
+/// let conn1 = template.open().await?;
+/// let conn2 = template.open().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Template<E = rusqlite::Error> {
+    bytes: Vec<u8>,
+    builder: Box<BuilderFn<E>>,
+}
+
+impl <E> std::fmt::Debug for Template<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Template")
+            .field("size", &self.bytes.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl <E: Send + 'static> Template<E> {
+    /// Create and migrate a new in-memory template database, using the
+    /// [`ConnectionBuilder`] returned from the given function.
+    pub async fn new<F>(builder: F) -> Result<Self, ConnectionBuilderError<E>>
+    where F: Fn() -> ConnectionBuilder<E> + Send + Sync + 'static
+    {
+        let conn = builder().open_in_memory().await?;
+        let bytes = serialize(&conn).await?;
+        Ok(Template { bytes, builder: Box::new(builder) })
+    }
+
+    /// Open a new, independent in-memory copy of the template database.
+    pub async fn open(&self) -> Result<Connection, ConnectionBuilderError<E>> {
+        (self.builder)().open_from_bytes(self.bytes.clone()).await
+    }
+}
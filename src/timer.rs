@@ -0,0 +1,120 @@
+use std::cmp::Reverse;
+use std::collections::{ BinaryHeap, HashMap };
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{ Condvar, Mutex, OnceLock };
+use std::task::{ Context, Poll, Waker };
+use std::time::{ Duration, Instant };
+
+/// A runtime agnostic future which completes after the given duration. Every
+/// sleep is handled by a single timer thread, which is spawned the first time
+/// that one is needed. Dropping a sleep cancels it.
+pub(crate) fn sleep(duration: Duration) -> Sleep {
+    Sleep { deadline: Instant::now() + duration, id: None }
+}
+
+pub(crate) struct Sleep {
+    deadline: Instant,
+    // Set once the sleep has been handed to the timer thread.
+    id: Option<u64>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let timer = timer();
+        let mut state = timer.state.lock().unwrap();
+
+        if Instant::now() >= self.deadline {
+            if let Some(id) = self.id.take() {
+                state.wakers.remove(&id);
+            }
+            return Poll::Ready(())
+        }
+
+        match self.id {
+            Some(id) => {
+                state.wakers.insert(id, cx.waker().clone());
+            },
+            None => {
+                let id = state.next_id;
+                state.next_id += 1;
+                state.wakers.insert(id, cx.waker().clone());
+                // Wake the timer thread if it needs to wait for less time now.
+                if state.deadlines.peek().is_none_or(|Reverse((at, _))| self.deadline < *at) {
+                    timer.changed.notify_one();
+                }
+                state.deadlines.push(Reverse((self.deadline, id)));
+                self.id = Some(id);
+            },
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        // The deadline is left in the heap, and is skipped once it's reached.
+        if let Some(id) = self.id {
+            timer().state.lock().unwrap().wakers.remove(&id);
+        }
+    }
+}
+
+struct Timer {
+    state: Mutex<State>,
+    // Notified when a deadline earlier than any other is added.
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    // Pending deadlines, soonest first, with the ID of the sleep they belong to.
+    deadlines: BinaryHeap<Reverse<(Instant, u64)>>,
+    // Wakers for sleeps which haven't been dropped or completed.
+    wakers: HashMap<u64, Waker>,
+    next_id: u64,
+}
+
+// Get hold of the timer, spawning its thread if this is the first time.
+fn timer() -> &'static Timer {
+    static TIMER: OnceLock<Timer> = OnceLock::new();
+    TIMER.get_or_init(|| {
+        std::thread::Builder::new()
+            .name("sqliter timer".to_owned())
+            .spawn(run)
+            .expect("should be able to spawn timer thread");
+        Timer { state: Mutex::new(State::default()), changed: Condvar::new() }
+    })
+}
+
+// Wake each sleep once its deadline has passed, forever.
+fn run() {
+    let timer = timer();
+    let mut state = timer.state.lock().unwrap();
+    loop {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        while let Some(&Reverse((at, id))) = state.deadlines.peek() {
+            if at > now {
+                break
+            }
+            state.deadlines.pop();
+            due.extend(state.wakers.remove(&id));
+        }
+
+        // Don't hold the lock while waking, in case that polls a sleep.
+        if !due.is_empty() {
+            drop(state);
+            due.into_iter().for_each(Waker::wake);
+            state = timer.state.lock().unwrap();
+            continue
+        }
+
+        state = match state.deadlines.peek() {
+            Some(&Reverse((at, _))) => timer.changed.wait_timeout(state, at - now).unwrap().0,
+            None => timer.changed.wait(state).unwrap(),
+        };
+    }
+}